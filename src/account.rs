@@ -0,0 +1,506 @@
+use std::{path::PathBuf, sync::Arc};
+
+use matrix_sdk::{
+    ruma::{OwnedRoomId, RoomId},
+    Client, SessionChange,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, watch},
+    task::JoinHandle,
+    time::{self, Duration, Instant},
+};
+
+use crate::{
+    events::{AccountEvent, AccountId, EventSender, EventTarget},
+    login::{
+        lock::ProfileLock,
+        login,
+        login_new::login_again_with_sso,
+        oidc::{login_with_oidc, refresh_session, DEFAULT_TOKEN_LIFETIME},
+        persist_session::{
+            logout_on_homeserver, persist_oidc_session, persist_session_tokens, remove_session,
+            LoginMethod,
+        },
+        session_file::SessionFile,
+        sync_state::SyncState,
+        LoginOptions,
+    },
+    sync::{sync, Connection, Viewport},
+    ui_elements::{
+        app::App,
+        chat::{self, MessageCache, Messages},
+    },
+};
+
+/// A logged-in account, syncing in the background.
+///
+/// If another instance of the client uses its profile, the account is
+/// read-only: it doesn't sync and nothing is written to its session or store.
+///
+/// The background tasks of the account don't change it, they send
+/// [`AccountEvent`]s that the app applies with [`EventTarget::apply()`].
+pub struct Account {
+    id: AccountId,
+
+    /// The client of the account.
+    pub client: Client,
+
+    /// The file where the session of the account is persisted.
+    pub session_file: SessionFile,
+
+    /// The state of the sync, written apart from the session.
+    sync_state: Arc<SyncState>,
+
+    /// The messages received so far.
+    messages: Messages,
+
+    /// Writes the latest messages to the store.
+    message_cache: MessageCache,
+
+    /// Sends the events of the account to the app.
+    events: EventSender,
+
+    /// The background sync loop, if the account isn't read-only.
+    sync: Option<JoinHandle<anyhow::Result<()>>>,
+
+    /// The state of the connection of the sync loop.
+    connection: Connection,
+
+    /// What the user sees of the account, followed by sliding sync.
+    viewport: watch::Sender<Viewport>,
+
+    /// The task refreshing and persisting the tokens.
+    session_watcher: JoinHandle<()>,
+
+    /// The OpenID Connect provider the account logged in with, if any.
+    oidc_issuer: Option<String>,
+
+    /// How the account logged in, to do it again after a soft logout.
+    login_method: LoginMethod,
+
+    /// Set when the homeserver invalidated the access token, to whether it
+    /// was a soft logout.
+    logged_out: Option<bool>,
+
+    /// The lock of the profile of the account.
+    lock: ProfileLock,
+}
+
+impl Account {
+    /// Pick a profile, log in with it and start syncing.
+    ///
+    /// `open` contains the session files of the accounts already logged in.
+    pub async fn login(
+        app: &mut App,
+        options: &LoginOptions,
+        open: &[PathBuf],
+    ) -> anyhow::Result<Self> {
+        let (client, legacy_sync_token, session_file, lock) = login(app, options, open).await?;
+
+        let sync_state = Arc::new(SyncState::load(&session_file, lock.is_read_only()).await);
+        // The instance using the profile moves the legacy sync token.
+        if let (None, Some(sync_token), false) = (
+            sync_state.sync_token(),
+            legacy_sync_token,
+            lock.is_read_only(),
+        ) {
+            sync_state.set_sync_token(sync_token);
+        }
+
+        let (oidc_issuer, login_method) = match session_file.read().await {
+            Ok(session) => (session.oidc.map(|oidc| oidc.issuer), session.login_method),
+            Err(_) => (None, LoginMethod::default()),
+        };
+
+        let id = AccountId::unique();
+        let events = app.event_sender(id);
+        let session_watcher = tokio::spawn(watch_session(
+            client.clone(),
+            session_file.clone(),
+            events.clone(),
+            // The instance using the profile refreshes the tokens.
+            oidc_issuer.is_some() && !lock.is_read_only(),
+        ));
+
+        // The messages cached at the last run are shown until new ones come.
+        let messages = Messages::load(&client).await;
+        let message_cache = MessageCache::new(client.clone());
+
+        let viewport = watch::Sender::new(Viewport::default());
+        let sync = if lock.is_read_only() {
+            None
+        } else {
+            let result = sync(
+                app,
+                client.clone(),
+                sync_state.clone(),
+                events.clone(),
+                viewport.subscribe(),
+            )
+            .await;
+
+            match result {
+                Ok(sync) => Some(sync),
+                Err(error) => {
+                    // The account is never added, its events would stay
+                    // pending.
+                    session_watcher.abort();
+                    app.drop_pending_events(id);
+                    return Err(error);
+                }
+            }
+        };
+
+        // Let's attach a handler for incoming room messages.
+        client.add_event_handler_context(events.clone());
+        client.add_event_handler(chat::on_room_message);
+
+        Ok(Self {
+            id,
+            client,
+            session_file,
+            sync_state,
+            messages,
+            message_cache,
+            events,
+            sync,
+            connection: Connection::Connecting,
+            viewport,
+            session_watcher,
+            oidc_issuer,
+            login_method,
+            logged_out: None,
+            lock,
+        })
+    }
+
+    /// The ID of the account, in the events sent to the app.
+    pub fn id(&self) -> AccountId {
+        self.id
+    }
+
+    /// Sends events about this account to the app.
+    pub fn events(&self) -> EventSender {
+        self.events.clone()
+    }
+
+    fn push_message(&mut self, room_id: OwnedRoomId, message: String) {
+        let latest = self.messages.push(room_id.clone(), message).to_vec();
+        if self.is_read_only() {
+            return;
+        }
+
+        // Keep the latest messages in the store, to show them at the next start.
+        self.message_cache.cache(room_id, latest);
+    }
+
+    /// The messages received in the given room.
+    pub fn messages(&self, room_id: &RoomId) -> &[String] {
+        self.messages.get(room_id)
+    }
+
+    /// The name shown for the account.
+    pub fn name(&self) -> String {
+        let name = self
+            .client
+            .user_id()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| self.session_file.path().to_string_lossy().into_owned());
+
+        if self.is_read_only() {
+            format!("{name} (read-only)")
+        } else {
+            name
+        }
+    }
+
+    /// Whether another instance uses the profile of this account.
+    pub fn is_read_only(&self) -> bool {
+        self.lock.is_read_only()
+    }
+
+    /// Whether the sync loop stopped.
+    pub fn sync_finished(&self) -> bool {
+        self.sync.as_ref().is_some_and(JoinHandle::is_finished)
+    }
+
+    /// The state of the connection to the homeserver, or `None` if the
+    /// account doesn't sync.
+    pub fn connection(&self) -> Option<&Connection> {
+        self.sync.as_ref().map(|_| &self.connection)
+    }
+
+    /// Let the sync know what the user sees.
+    pub fn set_viewport(&self, viewport: Viewport) {
+        self.viewport.send_if_modified(|current| {
+            if *current == viewport {
+                return false;
+            }
+            *current = viewport;
+            true
+        });
+    }
+
+    fn stop_sync(&self) {
+        if let Some(sync) = &self.sync {
+            sync.abort();
+        }
+    }
+
+    /// Whether the homeserver invalidated the access token, and if so whether
+    /// it was a soft logout.
+    pub fn logged_out(&self) -> Option<bool> {
+        self.logged_out
+    }
+
+    /// The OpenID Connect provider the account logged in with, if any.
+    pub fn oidc_issuer(&self) -> Option<&str> {
+        self.oidc_issuer.as_deref()
+    }
+
+    /// Log in again after a soft logout, keeping the same device and store,
+    /// then restart the sync.
+    pub async fn reauthenticate(&mut self, app: &mut App, password: &str) -> anyhow::Result<()> {
+        if self.is_read_only() {
+            anyhow::bail!("This account is read-only, log in again from the instance using it.");
+        }
+        let (Some(user_id), Some(device_id)) = (self.client.user_id(), self.client.device_id())
+        else {
+            anyhow::bail!("This account has no session to restore.");
+        };
+
+        self.client
+            .matrix_auth()
+            .login_username(user_id, password)
+            .device_id(device_id.as_str())
+            .request_refresh_token()
+            .await?;
+
+        let session = self
+            .client
+            .matrix_auth()
+            .session()
+            .expect("A logged-in client should have a session");
+        persist_session_tokens(&self.session_file, session.tokens).await?;
+
+        self.restart_sync(app).await
+    }
+
+    /// Whether the account logged in with SSO, so it can't log in again with
+    /// a password.
+    pub fn uses_sso(&self) -> bool {
+        matches!(
+            self.login_method,
+            LoginMethod::Sso { .. } | LoginMethod::SsoToken
+        )
+    }
+
+    /// Log in again with SSO after a soft logout, the way the account first
+    /// did and keeping the same device and store, then restart the sync.
+    pub async fn reauthenticate_sso(&mut self, app: &mut App) -> anyhow::Result<()> {
+        if self.is_read_only() {
+            anyhow::bail!("This account is read-only, log in again from the instance using it.");
+        }
+        let Some(device_id) = self.client.device_id() else {
+            anyhow::bail!("This account has no session to restore.");
+        };
+
+        login_again_with_sso(app, &self.client, &self.login_method, device_id).await?;
+
+        let session = self
+            .client
+            .matrix_auth()
+            .session()
+            .expect("A logged-in client should have a session");
+        persist_session_tokens(&self.session_file, session.tokens).await?;
+
+        self.restart_sync(app).await
+    }
+
+    /// Log in again with the OpenID Connect provider after a soft logout,
+    /// keeping the same device and store, then restart the sync.
+    pub async fn reauthenticate_oidc(&mut self, app: &mut App) -> anyhow::Result<()> {
+        if self.is_read_only() {
+            anyhow::bail!("This account is read-only, log in again from the instance using it.");
+        }
+        let (Some(issuer), Some(device_id)) = (&self.oidc_issuer, self.client.device_id()) else {
+            anyhow::bail!("This account has no OpenID Connect session to restore.");
+        };
+
+        let oidc = login_with_oidc(app, &self.client, issuer, Some(device_id)).await?;
+
+        let session = self
+            .client
+            .matrix_auth()
+            .session()
+            .expect("A logged-in client should have a session");
+        persist_oidc_session(&self.session_file, session.tokens, oidc).await?;
+
+        self.restart_sync(app).await
+    }
+
+    /// Start the sync again after logging in again.
+    async fn restart_sync(&mut self, app: &mut App) -> anyhow::Result<()> {
+        self.logged_out = None;
+
+        self.stop_sync();
+        self.sync = Some(
+            sync(
+                app,
+                self.client.clone(),
+                self.sync_state.clone(),
+                self.events.clone(),
+                self.viewport.subscribe(),
+            )
+            .await?,
+        );
+
+        Ok(())
+    }
+
+    /// Log out on the homeserver, then remove the session and its store.
+    ///
+    /// If the homeserver can't log us out, the account is left as it was and
+    /// keeps syncing.
+    pub async fn logout(&self) -> anyhow::Result<()> {
+        if self.is_read_only() {
+            anyhow::bail!("This account is read-only, log out from the instance using it.");
+        }
+        logout_on_homeserver(&self.client).await?;
+
+        self.stop_sync();
+        self.sync_state.discard();
+        self.message_cache.discard();
+        remove_session(&self.session_file).await
+    }
+
+    /// Remove the session and its store, without contacting the homeserver.
+    ///
+    /// This is used when the homeserver already logged us out. A read-only
+    /// account leaves the removal to the instance using it.
+    pub async fn forget(self) -> anyhow::Result<()> {
+        self.stop_sync();
+        self.sync_state.discard();
+        self.message_cache.discard();
+        if self.is_read_only() {
+            return Ok(());
+        }
+        remove_session(&self.session_file).await
+    }
+
+    /// Stop syncing and write the latest sync token and messages, before
+    /// quitting.
+    pub async fn close(&self) -> anyhow::Result<()> {
+        self.stop_sync();
+        if self.is_read_only() {
+            return Ok(());
+        }
+        self.message_cache.flush().await;
+        self.sync_state.flush().await
+    }
+
+    /// Wait for the sync loop to stop and return its result.
+    pub async fn sync_result(&mut self) -> anyhow::Result<()> {
+        match &mut self.sync {
+            Some(sync) => sync.await?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl EventTarget for Account {
+    fn id(&self) -> AccountId {
+        self.id
+    }
+
+    fn apply(&mut self, event: AccountEvent) -> Option<String> {
+        match event {
+            AccountEvent::Connection(connection) => self.connection = connection,
+            AccountEvent::Message { room_id, message } => self.push_message(room_id, message),
+            AccountEvent::Sent {
+                room_id,
+                body,
+                result,
+            } => {
+                if let Err(error) = result {
+                    return Some(format!(
+                        "Could not send the message \"{body}\" to {room_id}: {error}"
+                    ));
+                }
+            }
+            AccountEvent::LoggedOut { soft_logout } => self.logged_out = Some(soft_logout),
+            // The app only needs to redraw.
+            AccountEvent::RoomsUpdated | AccountEvent::SyncStopped | AccountEvent::Tick => {}
+        }
+
+        None
+    }
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        self.stop_sync();
+        self.session_watcher.abort();
+    }
+}
+
+/// Persist the tokens when they are refreshed, and notice when the homeserver
+/// invalidates them.
+///
+/// With `refresh_oidc`, the tokens of the OpenID Connect session are also
+/// refreshed before they expire, and when the homeserver says they did.
+async fn watch_session(
+    client: Client,
+    session_file: SessionFile,
+    events: EventSender,
+    refresh_oidc: bool,
+) {
+    let mut changes = client.subscribe_to_session_changes();
+    // The tokens were refreshed when restoring the session.
+    let mut next_refresh = Instant::now() + refresh_delay(DEFAULT_TOKEN_LIFETIME);
+
+    loop {
+        let change = tokio::select! {
+            change = changes.recv() => change,
+            () = time::sleep_until(next_refresh), if refresh_oidc => {
+                next_refresh = Instant::now() + match refresh_session(&client, &session_file).await {
+                    Ok(expires_in) => refresh_delay(expires_in),
+                    // The provider can't be reached, try again soon.
+                    Err(_) => OIDC_RETRY_DELAY,
+                };
+                continue;
+            }
+        };
+
+        match change {
+            // The token expired before we refreshed it, maybe while offline.
+            Ok(SessionChange::UnknownToken { soft_logout: true }) if refresh_oidc => {
+                match refresh_session(&client, &session_file).await {
+                    Ok(expires_in) => next_refresh = Instant::now() + refresh_delay(expires_in),
+                    Err(_) => events.send(AccountEvent::LoggedOut { soft_logout: true }),
+                }
+            }
+            Ok(SessionChange::TokensRefreshed) => {
+                if let Some(session) = client.matrix_auth().session() {
+                    // If this fails the old tokens stay on disk, and the
+                    // refresh token will be used again at the next start.
+                    let _ = persist_session_tokens(&session_file, session.tokens).await;
+                }
+            }
+            Ok(SessionChange::UnknownToken { soft_logout }) => {
+                events.send(AccountEvent::LoggedOut { soft_logout });
+            }
+            Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// How long to wait before retrying to refresh an OpenID Connect session.
+const OIDC_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// When to refresh an access token valid for `expires_in`, leaving time for
+/// slow requests.
+fn refresh_delay(expires_in: Duration) -> Duration {
+    expires_in * 3 / 4
+}
@@ -0,0 +1,80 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use matrix_sdk::ruma::{IdParseError, OwnedUserId, UserId};
+
+use crate::{
+    config::Config,
+    login::{default_data_dir, LoginOptions},
+};
+
+/// A Matrix client for the terminal.
+///
+/// What isn't given on the command line is asked interactively.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The profile to use, created if it doesn't exist.
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// The folder containing the profiles and their stores.
+    #[arg(long, global = true, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// The URL of the homeserver to log in to, instead of discovering it.
+    #[arg(long, global = true, value_name = "URL")]
+    pub homeserver: Option<String>,
+
+    /// The Matrix ID to log in with, like @alice:example.org.
+    #[arg(long, global = true, value_name = "MATRIX_ID", value_parser = parse_user_id)]
+    pub user: Option<OwnedUserId>,
+
+    /// Write the logs to this file.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Subcommand)]
+pub enum Command {
+    /// Log in or restore the session, then open the client. This is the
+    /// default.
+    #[default]
+    Run,
+
+    /// Log in and keep the session for later, without opening the client.
+    Login,
+
+    /// Log out, removing the session and its store, after offering to export
+    /// the room keys.
+    Logout,
+
+    /// Show the account the profile is logged in with.
+    Whoami,
+}
+
+impl Cli {
+    /// The options to log in with.
+    ///
+    /// The data folder given here takes precedence over the one in the
+    /// configuration.
+    pub fn login_options(&self, config: &Config) -> LoginOptions {
+        LoginOptions {
+            data_dir: self
+                .data_dir
+                .clone()
+                .or_else(|| config.data_dir.clone())
+                .unwrap_or_else(default_data_dir),
+            profile: self.profile.clone(),
+            homeserver: self.homeserver.clone(),
+            user: self.user.clone(),
+        }
+    }
+}
+
+fn parse_user_id(user_id: &str) -> Result<OwnedUserId, IdParseError> {
+    UserId::parse(user_id)
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail};
+use crossterm::event::KeyCode;
+use matrix_sdk::{reqwest::Url, ruma::api::client::filter::FilterDefinition};
+use ratatui::style::Color;
+use serde::{de, Deserialize, Deserializer};
+
+/// The default configuration, written when there is none. It documents every
+/// option.
+const DEFAULT_CONFIG: &str = include_str!("../config.example.toml");
+
+/// The configuration of the client.
+///
+/// See `config.example.toml` for the documentation of each option.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The folder containing the profiles and their stores.
+    pub data_dir: Option<PathBuf>,
+
+    /// The name of the devices created when logging in.
+    pub device_display_name: String,
+
+    pub sync: SyncConfig,
+
+    pub colors: Colors,
+
+    pub keys: KeyBindings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: None,
+            device_display_name: "login client".to_owned(),
+            sync: SyncConfig::default(),
+            colors: Colors::default(),
+            keys: KeyBindings::default(),
+        }
+    }
+}
+
+/// What is received when syncing.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    /// Whether to only receive the members of the rooms that are needed.
+    pub lazy_load_members: bool,
+
+    /// The maximum number of events received per room.
+    pub timeline_limit: Option<u32>,
+
+    /// Whether to use sliding sync when the homeserver supports it.
+    pub sliding_sync: bool,
+
+    /// The sliding sync proxy to use, instead of the one advertised by the
+    /// homeserver.
+    pub sliding_sync_proxy: Option<String>,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            lazy_load_members: true,
+            timeline_limit: None,
+            sliding_sync: false,
+            sliding_sync_proxy: None,
+        }
+    }
+}
+
+impl SyncConfig {
+    /// The filter to sync with.
+    pub fn filter(&self) -> FilterDefinition {
+        let mut filter = if self.lazy_load_members {
+            FilterDefinition::with_lazy_loading()
+        } else {
+            FilterDefinition::default()
+        };
+        filter.room.timeline.limit = self.timeline_limit.map(Into::into);
+
+        filter
+    }
+}
+
+/// The colors of the interface.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Colors {
+    /// The color of the selection, the input fields and the popups.
+    #[serde(deserialize_with = "deserialize_color")]
+    pub accent: Color,
+
+    /// The color of the error popups.
+    #[serde(deserialize_with = "deserialize_color")]
+    pub error: Color,
+
+    /// The color of the information popups.
+    #[serde(deserialize_with = "deserialize_color")]
+    pub info: Color,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Self {
+            accent: Color::Yellow,
+            error: Color::Red,
+            info: Color::Blue,
+        }
+    }
+}
+
+fn deserialize_color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+    let color = String::deserialize(deserializer)?;
+    Color::from_str(&color).map_err(|_| de::Error::custom(format!("invalid color '{color}'")))
+}
+
+/// The keys of the room list.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    pub quit: char,
+    pub up: char,
+    pub down: char,
+    pub accounts: char,
+    pub passphrase: char,
+    pub stores: char,
+    pub logout: char,
+    pub reload_config: char,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            quit: 'q',
+            up: 'k',
+            down: 'j',
+            accounts: 'a',
+            passphrase: 'P',
+            stores: 'S',
+            logout: 'L',
+            reload_config: 'r',
+        }
+    }
+}
+
+/// What a key does in the room list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    Up,
+    Down,
+    Open,
+    Accounts,
+    Passphrase,
+    Stores,
+    Logout,
+    ReloadConfig,
+}
+
+impl KeyBindings {
+    /// The action bound to the key, if any.
+    pub fn action(&self, code: KeyCode) -> Option<Action> {
+        let action = match code {
+            KeyCode::Esc => Action::Quit,
+            KeyCode::Up => Action::Up,
+            KeyCode::Down => Action::Down,
+            KeyCode::Enter => Action::Open,
+            KeyCode::Char(c) => self.bindings().into_iter().find(|&(key, _)| key == c)?.1,
+            _ => return None,
+        };
+
+        Some(action)
+    }
+
+    fn bindings(&self) -> [(char, Action); 8] {
+        [
+            (self.quit, Action::Quit),
+            (self.up, Action::Up),
+            (self.down, Action::Down),
+            (self.accounts, Action::Accounts),
+            (self.passphrase, Action::Passphrase),
+            (self.stores, Action::Stores),
+            (self.logout, Action::Logout),
+            (self.reload_config, Action::ReloadConfig),
+        ]
+    }
+}
+
+/// The file of the configuration, in the config folder.
+pub fn path() -> PathBuf {
+    dirs::config_dir()
+        .expect("no config_dir directory found")
+        .join("persist_session")
+        .join("config.toml")
+}
+
+impl Config {
+    /// Load the configuration, after writing the default one if there is
+    /// none.
+    ///
+    /// The error says what is wrong in the file.
+    pub fn load() -> anyhow::Result<Self> {
+        let path = path();
+        if !path.exists() {
+            write_default(&path)?;
+        }
+
+        let serialized = fs::read_to_string(&path)?;
+        let config: Config = toml::from_str(&serialized)
+            .map_err(|err| anyhow!("Invalid configuration in '{}':\n{err}", path.display()))?;
+        config
+            .validate()
+            .map_err(|err| anyhow!("Invalid configuration in '{}':\n{err}", path.display()))?;
+
+        Ok(config)
+    }
+
+    /// Check what can't be checked when parsing.
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.device_display_name.trim().is_empty() {
+            bail!("`device_display_name` can't be empty.");
+        }
+
+        if self.sync.timeline_limit == Some(0) {
+            bail!("`sync.timeline_limit` must be at least 1.");
+        }
+
+        if let Some(proxy) = &self.sync.sliding_sync_proxy {
+            if Url::parse(proxy).is_err() {
+                bail!("`sync.sliding_sync_proxy` is not a valid URL: '{proxy}'.");
+            }
+        }
+
+        let mut keys = HashSet::new();
+        for (key, action) in self.keys.bindings() {
+            if !keys.insert(key) {
+                bail!("The key '{key}' is bound to several actions, including {action:?}.");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn write_default(path: &Path) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, DEFAULT_CONFIG)?;
+
+    Ok(())
+}
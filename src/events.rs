@@ -0,0 +1,355 @@
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use matrix_sdk::ruma::OwnedRoomId;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::sync::Connection;
+
+/// Identifies an account for as long as the program runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AccountId(u64);
+
+impl AccountId {
+    /// A new ID, different from all the previous ones.
+    pub fn unique() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Something that happened in the background to an account.
+///
+/// The sync loops, the event handlers and the other tasks send these to the
+/// app, which owns the state of the accounts and updates it.
+#[derive(Debug)]
+pub enum AccountEvent {
+    /// A sync response was received, the rooms may have changed.
+    RoomsUpdated,
+
+    /// The state of the connection to the homeserver changed.
+    Connection(Connection),
+
+    /// The sync loop stopped.
+    SyncStopped,
+
+    /// A text message was received in a room.
+    Message {
+        room_id: OwnedRoomId,
+        message: String,
+    },
+
+    /// Sending a message to a room finished.
+    Sent {
+        room_id: OwnedRoomId,
+        body: String,
+        result: Result<(), String>,
+    },
+
+    /// The homeserver invalidated the access token, and said whether it was a
+    /// soft logout.
+    LoggedOut { soft_logout: bool },
+
+    /// Nothing changed, but what is shown depends on the time, like a
+    /// countdown.
+    Tick,
+}
+
+/// Sends the events of an account to the app.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    account: AccountId,
+    sender: UnboundedSender<(AccountId, AccountEvent)>,
+}
+
+impl EventSender {
+    pub fn new(account: AccountId, sender: UnboundedSender<(AccountId, AccountEvent)>) -> Self {
+        Self { account, sender }
+    }
+
+    /// Send the event, unless the app is gone.
+    pub fn send(&self, event: AccountEvent) {
+        let _ = self.sender.send((self.account, event));
+    }
+}
+
+/// What the events of an account apply to.
+pub trait EventTarget {
+    /// The ID of the account, in its events.
+    fn id(&self) -> AccountId;
+
+    /// Update the account with an event of its background tasks.
+    ///
+    /// Returns the error to show to the user, if any.
+    fn apply(&mut self, event: AccountEvent) -> Option<String>;
+}
+
+/// Routes the events of the background tasks to their accounts.
+///
+/// The events of accounts that aren't added yet, like during their initial
+/// sync, are kept until they are. The events of the other accounts missing
+/// from the list, which were removed, are dropped.
+#[derive(Debug)]
+pub struct EventRouter {
+    receiver: UnboundedReceiver<(AccountId, AccountEvent)>,
+
+    /// Cloned for the background tasks of each account.
+    sender: UnboundedSender<(AccountId, AccountEvent)>,
+
+    /// The events of the accounts that aren't added yet.
+    pending: Vec<(AccountId, AccountEvent)>,
+
+    /// The accounts that aren't added yet, whose events are kept.
+    expected: HashSet<AccountId>,
+
+    /// The errors of the background tasks, to show to the user.
+    errors: Vec<String>,
+}
+
+impl Default for EventRouter {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        Self {
+            receiver,
+            sender,
+            pending: Vec::new(),
+            expected: HashSet::new(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl EventRouter {
+    /// Where the background tasks of the given account send their events.
+    ///
+    /// The account is expected to be added, see [`EventRouter::expect()`].
+    pub fn sender(&mut self, account: AccountId) -> EventSender {
+        self.expect(account);
+        EventSender::new(account, self.sender.clone())
+    }
+
+    /// Keep the events of the account until it is added, or until its
+    /// pending events are dropped.
+    ///
+    /// This is needed for an account removed for a while, like when it logs
+    /// in again.
+    pub fn expect(&mut self, account: AccountId) {
+        self.expected.insert(account);
+    }
+
+    /// Wait for the next event.
+    pub async fn recv(&mut self) -> Option<(AccountId, AccountEvent)> {
+        self.receiver.recv().await
+    }
+
+    /// Apply the event to its account, or keep it until the account is added.
+    ///
+    /// The events of accounts that aren't expected, like the results of
+    /// tasks that ended after a logout, are dropped.
+    pub fn route(&mut self, accounts: &mut [impl EventTarget], id: AccountId, event: AccountEvent) {
+        let Some(account) = accounts.iter_mut().find(|account| account.id() == id) else {
+            // Ticks only matter for what is shown now.
+            if self.expected.contains(&id) && !matches!(event, AccountEvent::Tick) {
+                self.pending.push((id, event));
+            }
+            return;
+        };
+
+        if let Some(error) = account.apply(event) {
+            self.errors.push(error);
+        }
+    }
+
+    /// Route the events already received, without waiting for more.
+    pub fn route_received(&mut self, accounts: &mut [impl EventTarget]) {
+        while let Ok((id, event)) = self.receiver.try_recv() {
+            self.route(accounts, id, event);
+        }
+    }
+
+    /// Add the account at the given position, with the events received while
+    /// it wasn't there.
+    pub fn insert<T: EventTarget>(&mut self, accounts: &mut Vec<T>, idx: usize, account: T) {
+        let id = account.id();
+        accounts.insert(idx, account);
+        self.expected.remove(&id);
+
+        let (events, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|(account, _)| *account == id);
+        self.pending = pending;
+        for (id, event) in events {
+            self.route(accounts, id, event);
+        }
+    }
+
+    /// Forget the events of an account that was removed, or never added.
+    ///
+    /// The events already received are routed first, so none is left behind.
+    pub fn drop_pending(&mut self, accounts: &mut [impl EventTarget], id: AccountId) {
+        self.route_received(accounts);
+        self.expected.remove(&id);
+        self.pending.retain(|(account, _)| *account != id);
+    }
+
+    /// The errors of the background tasks since the last call.
+    pub fn take_errors(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{owned_room_id, OwnedRoomId};
+
+    use super::{AccountEvent, AccountId, EventRouter, EventTarget};
+
+    /// An account recording the messages it receives.
+    struct Recorder {
+        id: AccountId,
+        messages: Vec<String>,
+    }
+
+    impl Recorder {
+        fn new() -> Self {
+            Self {
+                id: AccountId::unique(),
+                messages: Vec::new(),
+            }
+        }
+    }
+
+    impl EventTarget for Recorder {
+        fn id(&self) -> AccountId {
+            self.id
+        }
+
+        fn apply(&mut self, event: AccountEvent) -> Option<String> {
+            match event {
+                AccountEvent::Message { message, .. } => self.messages.push(message),
+                AccountEvent::Sent { result, .. } => return result.err(),
+                _ => {}
+            }
+
+            None
+        }
+    }
+
+    fn room_id() -> OwnedRoomId {
+        owned_room_id!("!room:example.org")
+    }
+
+    fn message(message: &str) -> AccountEvent {
+        AccountEvent::Message {
+            room_id: room_id(),
+            message: message.to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_to_the_account() {
+        let mut router = EventRouter::default();
+        let mut accounts = vec![Recorder::new(), Recorder::new()];
+        let sender = router.sender(accounts[1].id);
+
+        sender.send(message("hello"));
+        let (id, event) = router.recv().await.unwrap();
+        router.route(&mut accounts, id, event);
+
+        assert!(accounts[0].messages.is_empty());
+        assert_eq!(accounts[1].messages, ["hello"]);
+    }
+
+    #[test]
+    fn keeps_the_events_until_the_account_is_added() {
+        let mut router = EventRouter::default();
+        let mut accounts = vec![Recorder::new()];
+        let account = Recorder::new();
+        let sender = router.sender(account.id);
+
+        sender.send(message("first"));
+        sender.send(AccountEvent::Tick);
+        sender.send(message("second"));
+        router.route_received(&mut accounts);
+        assert!(accounts[0].messages.is_empty());
+
+        router.insert(&mut accounts, 0, account);
+        assert_eq!(accounts[0].messages, ["first", "second"]);
+        // Nothing is applied twice.
+        router.route_received(&mut accounts);
+        assert_eq!(accounts[0].messages, ["first", "second"]);
+        assert!(router.pending.is_empty());
+    }
+
+    #[test]
+    fn drops_the_events_of_accounts_never_added() {
+        let mut router = EventRouter::default();
+        let mut accounts: Vec<Recorder> = Vec::new();
+        let account = Recorder::new();
+        let sender = router.sender(account.id);
+
+        sender.send(message("lost"));
+        router.drop_pending(&mut accounts, account.id);
+        assert!(router.pending.is_empty());
+
+        router.insert(&mut accounts, 0, account);
+        assert!(accounts[0].messages.is_empty());
+    }
+
+    #[test]
+    fn collects_the_errors() {
+        let mut router = EventRouter::default();
+        let mut accounts = vec![Recorder::new()];
+        let id = accounts[0].id;
+
+        router.route(
+            &mut accounts,
+            id,
+            AccountEvent::Sent {
+                room_id: room_id(),
+                body: "hello".to_owned(),
+                result: Err("offline".to_owned()),
+            },
+        );
+
+        assert_eq!(router.take_errors(), ["offline"]);
+        assert!(router.take_errors().is_empty());
+    }
+
+    #[test]
+    fn drops_the_events_of_removed_accounts() {
+        let mut router = EventRouter::default();
+        let mut accounts = Vec::new();
+        let account = Recorder::new();
+        let id = account.id;
+        let sender = router.sender(id);
+        router.insert(&mut accounts, 0, account);
+
+        // Like the result of a message sent before a logout.
+        let account = accounts.remove(0);
+        sender.send(message("late"));
+        router.route_received(&mut accounts);
+        assert!(router.pending.is_empty());
+
+        // Unless the account is expected back.
+        router.expect(id);
+        sender.send(message("kept"));
+        router.route_received(&mut accounts);
+        router.insert(&mut accounts, 0, account);
+        assert_eq!(accounts[0].messages, ["kept"]);
+    }
+
+    #[test]
+    fn drops_the_events_of_unknown_accounts() {
+        let mut router = EventRouter::default();
+        let mut accounts = vec![Recorder::new()];
+
+        router.route(&mut accounts, AccountId::unique(), message("lost"));
+
+        assert!(router.pending.is_empty());
+        assert!(accounts[0].messages.is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use matrix_sdk::{ruma::api::client::error::ErrorKind, HttpError};
+
+/// The reasons a login can fail, as shown to the user.
+#[derive(Debug)]
+pub enum LoginError {
+    /// The username or the password is wrong.
+    WrongCredentials,
+
+    /// The account was deactivated.
+    UserDeactivated,
+
+    /// The homeserver doesn't know this user.
+    UnknownUser,
+
+    /// The homeserver could not be reached.
+    ServerUnreachable,
+
+    /// Too many attempts, the homeserver wants us to wait before retrying.
+    RateLimited {
+        /// How long to wait, if the homeserver told us.
+        retry_after: Option<Duration>,
+    },
+
+    /// Any other error.
+    Other(String),
+}
+
+impl From<&matrix_sdk::Error> for LoginError {
+    fn from(error: &matrix_sdk::Error) -> Self {
+        if let matrix_sdk::Error::Http(HttpError::Reqwest(_)) = error {
+            return LoginError::ServerUnreachable;
+        }
+
+        match error.client_api_error_kind() {
+            Some(ErrorKind::Forbidden { .. }) => LoginError::WrongCredentials,
+            Some(ErrorKind::UserDeactivated) => LoginError::UserDeactivated,
+            Some(ErrorKind::NotFound | ErrorKind::InvalidUsername) => LoginError::UnknownUser,
+            Some(ErrorKind::LimitExceeded { retry_after_ms }) => LoginError::RateLimited {
+                retry_after: *retry_after_ms,
+            },
+            _ => LoginError::Other(error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::WrongCredentials => write!(f, "Wrong username or password."),
+            LoginError::UserDeactivated => write!(f, "This account has been deactivated."),
+            LoginError::UnknownUser => write!(f, "This user doesn't exist on the homeserver."),
+            LoginError::ServerUnreachable => {
+                write!(
+                    f,
+                    "The homeserver could not be reached, check your connection."
+                )
+            }
+            LoginError::RateLimited { retry_after: None } => {
+                write!(
+                    f,
+                    "Too many attempts, please wait a bit before trying again."
+                )
+            }
+            LoginError::RateLimited {
+                retry_after: Some(delay),
+            } => write!(
+                f,
+                "Too many attempts, retrying in {} seconds…",
+                delay.as_secs()
+            ),
+            LoginError::Other(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+/// The reasons a persisted session can't be restored, as shown to the user.
+#[derive(Debug)]
+pub enum SessionError {
+    /// The session file can't be parsed.
+    Corrupt(String),
+
+    /// The session file was written by a newer version of the client.
+    TooNew(u64),
+
+    /// The store of the session was removed.
+    StoreMissing(PathBuf),
+
+    /// The store of the session can't be opened.
+    StoreCorrupt(String),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Corrupt(error) => write!(f, "The session file is corrupted: {error}"),
+            SessionError::TooNew(version) => write!(
+                f,
+                "The session file was written by a newer version of the client \
+                 (format version {version}), please update it."
+            ),
+            SessionError::StoreMissing(path) => write!(
+                f,
+                "The store of the session is missing, it was in '{}'.",
+                path.to_string_lossy()
+            ),
+            SessionError::StoreCorrupt(error) => {
+                write!(f, "The store of the session can't be opened: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use matrix_sdk::Client;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, ResponseTemplate,
+    };
+
+    use super::LoginError;
+    use crate::test_utils::{
+        client, error_response, mock_homeserver, rate_limited_response, unreachable_client,
+    };
+
+    /// Try to log in with a password, and map the error.
+    async fn login_error(client: &Client) -> LoginError {
+        let error = client
+            .matrix_auth()
+            .login_username("alice", "password")
+            .await
+            .expect_err("The login should fail");
+
+        LoginError::from(&error)
+    }
+
+    /// Log in against a homeserver answering to `/login` with `response`.
+    async fn login_error_with(response: ResponseTemplate) -> LoginError {
+        let server = mock_homeserver().await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/login"))
+            .respond_with(response)
+            .mount(&server)
+            .await;
+
+        login_error(&client(&server).await).await
+    }
+
+    #[tokio::test]
+    async fn forbidden_is_wrong_credentials() {
+        let error = login_error_with(error_response(403, "M_FORBIDDEN")).await;
+        assert!(matches!(error, LoginError::WrongCredentials), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn user_deactivated() {
+        let error = login_error_with(error_response(403, "M_USER_DEACTIVATED")).await;
+        assert!(matches!(error, LoginError::UserDeactivated), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn limit_exceeded_keeps_the_delay() {
+        let error = login_error_with(rate_limited_response(10)).await;
+        assert!(
+            matches!(
+                error,
+                LoginError::RateLimited {
+                    retry_after: Some(delay)
+                } if delay == Duration::from_millis(10)
+            ),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_refused_is_unreachable() {
+        let error = login_error(&unreachable_client().await).await;
+        assert!(matches!(error, LoginError::ServerUnreachable), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn other_errors_keep_their_message() {
+        let error = login_error_with(error_response(400, "M_UNRECOGNIZED")).await;
+        assert!(matches!(error, LoginError::Other(_)), "{error:?}");
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+
+use crate::ui_elements::{app::App, select_popup::select_popup};
+
+/// The instance holding the lock of a profile, as written in the lock file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Holder {
+    /// The ID of the process.
+    pid: u32,
+
+    /// When the process took the lock, in seconds since the Unix epoch.
+    started: u64,
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let minutes = now().saturating_sub(self.started) / 60;
+        write!(f, "process {}, started {minutes} minutes ago", self.pid)
+    }
+}
+
+/// An advisory lock on a profile, so a single instance of the client uses its
+/// session and store at a time.
+///
+/// The lock is released when this is dropped, or when the process exits.
+#[derive(Debug)]
+pub struct ProfileLock {
+    /// The locked file, or `None` if the profile is attached read-only while
+    /// another instance holds the lock.
+    file: Option<File>,
+}
+
+impl ProfileLock {
+    /// Whether the profile is used by another instance, and must not be
+    /// written to.
+    pub fn is_read_only(&self) -> bool {
+        self.file.is_none()
+    }
+}
+
+enum LockChoice {
+    ReadOnly,
+    Cancel,
+}
+
+impl fmt::Display for LockChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockChoice::ReadOnly => write!(f, "Open it read-only"),
+            LockChoice::Cancel => write!(f, "Cancel"),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether an instance of the client holds the lock of the profile in the
+/// given folder, this one included.
+pub fn is_locked(dir: &Path) -> bool {
+    let Ok(file) = OpenOptions::new().read(true).open(dir.join("lock")) else {
+        return false;
+    };
+
+    // Our shared lock is released when the file is closed.
+    file.try_lock_shared().is_err()
+}
+
+/// Lock the profile in the given folder.
+///
+/// If another instance holds the lock, the user is told which one and, if
+/// `allow_read_only` is set, is offered to attach read-only.
+pub async fn lock_profile(
+    app: &mut App,
+    dir: &Path,
+    allow_read_only: bool,
+) -> anyhow::Result<ProfileLock> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(dir.join("lock"))?;
+
+    if file.try_lock_exclusive().is_ok() {
+        let holder = Holder {
+            pid: process::id(),
+            started: now(),
+        };
+        file.set_len(0)?;
+        file.write_all(serde_json::to_string(&holder)?.as_bytes())?;
+
+        return Ok(ProfileLock { file: Some(file) });
+    }
+
+    let mut serialized = String::new();
+    let holder = match file.read_to_string(&mut serialized) {
+        Ok(_) => serde_json::from_str::<Holder>(&serialized).ok(),
+        Err(_) => None,
+    };
+    let message = match holder {
+        Some(holder) => {
+            format!("This profile is already used by another instance of the client ({holder}).")
+        }
+        None => "This profile is already used by another instance of the client.".to_owned(),
+    };
+
+    if !allow_read_only {
+        bail!(message);
+    }
+
+    let body = format!(
+        "{message}\n\nUsing it from two instances at once can corrupt its encryption keys. \
+         It can be opened read-only instead, showing the rooms known so far without syncing."
+    );
+    let choice = select_popup(
+        app,
+        "Profile in use",
+        &body,
+        vec![LockChoice::ReadOnly, LockChoice::Cancel],
+    )
+    .await?;
+
+    match choice {
+        LockChoice::ReadOnly => Ok(ProfileLock { file: None }),
+        LockChoice::Cancel => bail!(message),
+    }
+}
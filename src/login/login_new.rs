@@ -1,28 +1,47 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use matrix_sdk::{
     self,
-    ruma::api::client::session::get_login_types::v3::{IdentityProvider, LoginType},
-    Client,
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    reqwest::Url,
+    ruma::{
+        api::client::{
+            error::ErrorKind,
+            session::get_login_types::v3::{IdentityProvider, LoginType},
+        },
+        DeviceId, OwnedDeviceId, OwnedUserId, UserId,
+    },
+    Client, SessionMeta,
 };
 
-/// The initial device name when logging in with a device for the first time.
-const INITIAL_DEVICE_DISPLAY_NAME: &str = "login client";
+/// Where the homeserver redirects the browser after an SSO login without a
+/// local server. Nothing listens there, the user copies the URL instead.
+const SSO_REDIRECT_URL: &str = "http://localhost/sso-callback";
 
 use std::path::Path;
 
-use tokio::fs;
+use tokio::time::{self, Instant};
 
 use crate::{
-    login::persist_session::{build_client, FullSession},
+    login::{
+        error::LoginError,
+        lock::ProfileLock,
+        oidc::{login_with_oidc, OidcSession},
+        persist_session::{build_client, request_config, FullSession, LoginMethod},
+        register::register,
+        session_file::{new_passphrase, SessionFile},
+        LoginOptions,
+    },
     ui_elements::{
+        app::{App, Screen},
         info_popup::{info_popup, Type},
-        input_popup::input_popup,
+        input_popup::{input_popup, prefilled_input_popup, secret_input_popup},
+        select_popup::select_popup,
     },
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum LoginChoice {
     /// Login with username and password.
     Password,
@@ -32,15 +51,66 @@ enum LoginChoice {
 
     /// Login with a specific SSO identity provider.
     SsoIdp(IdentityProvider),
+
+    /// Login with SSO, pasting the login token instead of being redirected
+    /// to a local server.
+    SsoToken,
+
+    /// Create a new account.
+    Register,
+
+    /// Login with an existing access token.
+    AccessToken,
+
+    /// Login with the given OpenID Connect provider.
+    Oidc(String),
 }
 
 impl LoginChoice {
     /// Login with this login choice.
-    async fn login(&self, client: &Client) -> anyhow::Result<()> {
+    ///
+    /// `user_id` is the user ID entered to discover the homeserver, if any.
+    ///
+    /// Returns the OpenID Connect session to persist, if this choice uses one,
+    /// or `None` if the choice turned out to be unavailable.
+    async fn login(
+        &self,
+        app: &mut App,
+        client: &Client,
+        user_id: Option<&UserId>,
+    ) -> anyhow::Result<Option<Option<OidcSession>>> {
+        match self {
+            LoginChoice::Password => login_with_password(app, client, user_id).await?,
+            LoginChoice::Sso => login_with_sso(app, client, None, None).await?,
+            LoginChoice::SsoIdp(idp) => login_with_sso(app, client, Some(&idp.id), None).await?,
+            LoginChoice::SsoToken => login_with_sso_token(app, client, None, None).await?,
+            LoginChoice::Register => {
+                if !register(app, client, user_id).await? {
+                    return Ok(None);
+                }
+            }
+            LoginChoice::AccessToken => login_with_access_token(app, client).await?,
+            LoginChoice::Oidc(issuer) => {
+                return Ok(Some(Some(
+                    login_with_oidc(app, client, issuer, None).await?,
+                )))
+            }
+        }
+
+        Ok(Some(None))
+    }
+
+    /// How the session is logged in with this choice.
+    fn method(&self) -> LoginMethod {
         match self {
-            LoginChoice::Password => login_with_password(client).await,
-            LoginChoice::Sso => login_with_sso(client, None).await,
-            LoginChoice::SsoIdp(idp) => login_with_sso(client, Some(idp)).await,
+            LoginChoice::Password | LoginChoice::Register => LoginMethod::Password,
+            LoginChoice::Sso => LoginMethod::Sso { idp: None },
+            LoginChoice::SsoIdp(idp) => LoginMethod::Sso {
+                idp: Some(idp.id.clone()),
+            },
+            LoginChoice::SsoToken => LoginMethod::SsoToken,
+            LoginChoice::AccessToken => LoginMethod::AccessToken,
+            LoginChoice::Oidc(_) => LoginMethod::Oidc,
         }
     }
 }
@@ -51,17 +121,37 @@ impl fmt::Display for LoginChoice {
             LoginChoice::Password => write!(f, "Username and password"),
             LoginChoice::Sso => write!(f, "SSO"),
             LoginChoice::SsoIdp(idp) => write!(f, "SSO via {}", idp.name),
+            LoginChoice::SsoToken => write!(f, "SSO without a browser"),
+            LoginChoice::Register => write!(f, "Create account"),
+            LoginChoice::AccessToken => write!(f, "Access token"),
+            LoginChoice::Oidc(issuer) => write!(f, "OpenID Connect via {issuer}"),
         }
     }
 }
 
 /// Log in to the given homeserver and sync.
-pub async fn login_new(data_dir: &Path, session_file: &Path) -> anyhow::Result<Client> {
-    let (client, client_session) = build_client(data_dir).await?;
+///
+/// The returned lock keeps the profile in `data_dir` for this instance.
+pub async fn login_new(
+    app: &mut App,
+    data_dir: &Path,
+    session_file: &SessionFile,
+    options: &LoginOptions,
+) -> anyhow::Result<(Client, ProfileLock)> {
+    let (client, client_session, user_id, issuer, lock) =
+        build_client(app, data_dir, options).await?;
 
     let matrix_auth = client.matrix_auth();
     // First, let's figure out what login types are supported by the homeserver.
     let mut choices = Vec::new();
+
+    // Homeservers delegating their authentication to an OpenID Connect
+    // provider may still offer other login types for compatibility, but this
+    // is the preferred one.
+    if let Some(issuer) = issuer {
+        choices.push(LoginChoice::Oidc(issuer));
+    }
+
     let login_types = matrix_auth.get_login_types().await?.flows;
 
     for login_type in login_types {
@@ -75,8 +165,14 @@ pub async fn login_new(data_dir: &Path, session_file: &Path) -> anyhow::Result<C
                 } else {
                     choices.extend(sso.identity_providers.into_iter().map(LoginChoice::SsoIdp))
                 }
+                // Headless SSO logins paste the token anyway, but we can't
+                // always tell, like when the browser opens on another display.
+                if !is_headless() {
+                    choices.push(LoginChoice::SsoToken)
+                }
             }
-            // This is used for SSO, so it's not a separate choice.
+            // This is used for SSO, so it's not a separate choice. Headless SSO
+            // logins go through it.
             LoginType::Token(_) |
             // This is only for application services, ignore it here.
             LoginType::ApplicationService(_) => {},
@@ -85,16 +181,21 @@ pub async fn login_new(data_dir: &Path, session_file: &Path) -> anyhow::Result<C
         }
     }
 
-    match choices.len() {
-        0 => {
-            return Err(anyhow!(
-                "Homeserver login types incompatible with this client"
-            ))
-        }
-        1 => choices[0].login(&client).await?,
-        _ => offer_choices_and_login(&client, choices).await?,
+    if choices.is_empty() {
+        return Err(anyhow!(
+            "Homeserver login types incompatible with this client"
+        ));
     }
 
+    // Creating an account is always offered, the homeserver tells us if
+    // registration is disabled.
+    choices.push(LoginChoice::Register);
+    // Access tokens don't need the homeserver to support a login type.
+    choices.push(LoginChoice::AccessToken);
+
+    let (login_method, oidc) =
+        offer_choices_and_login(app, &client, choices, user_id.as_deref()).await?;
+
     // Persist the session to reuse it later.
     // This is not very secure, for simplicity. If the system provides a way of
     // storing secrets securely, it should be used instead.
@@ -102,14 +203,40 @@ pub async fn login_new(data_dir: &Path, session_file: &Path) -> anyhow::Result<C
     let user_session = matrix_auth
         .session()
         .expect("A logged-in client should have a session");
-    let serialized_session = serde_json::to_string(&FullSession {
+    let full_session = FullSession {
         client_session,
         user_session,
         sync_token: None,
-    })?;
-    fs::write(session_file, serialized_session).await?;
+        oidc,
+        login_method,
+    };
+
+    // The session can be protected by a passphrase, this encrypts it at rest.
+    // We are logged in already, so cancelling stores it unencrypted rather
+    // than leaving a device and a store without a session. The passphrase
+    // can be set later.
+    let protect = select_popup(
+        app,
+        "Protect the session?",
+        "The session file contains the access token and the key of the database. \
+         It can be encrypted with a master passphrase, which is asked at each start.",
+        vec![ProtectChoice::Passphrase, ProtectChoice::Plain],
+    )
+    .await;
+    if let Ok(ProtectChoice::Passphrase) = protect {
+        if let Ok(Some(passphrase)) = new_passphrase(app).await {
+            session_file.set_key(Some(&passphrase))?;
+        }
+    }
+    session_file.write(&full_session).await?;
 
-    println!("Session persisted in {}", session_file.to_string_lossy());
+    app.set_screen(
+        Screen::Login,
+        format!(
+            "Session persisted in {}",
+            session_file.path().to_string_lossy()
+        ),
+    )?;
 
     // After logging in, you might want to verify this session with another one (see
     // the `emoji_verification` example), or bootstrap cross-signing if this is your
@@ -117,91 +244,559 @@ pub async fn login_new(data_dir: &Path, session_file: &Path) -> anyhow::Result<C
     // you don't have access to your old sessions (see the
     // `cross_signing_bootstrap` example).
 
-    Ok(client)
+    Ok((client, lock))
 }
 
-/// Offer the given choices to the user and login with the selected option.
-async fn offer_choices_and_login(client: &Client, choices: Vec<LoginChoice>) -> anyhow::Result<()> {
-    let choice = loop {
-        let mut body = vec!["Several options are available to login with this homeserver:".into()];
-        for (idx, login_choice) in choices.iter().enumerate() {
-            body.push(format!("{idx}) {login_choice}"));
-        }
-        let header = "Enter your choice:";
-
-        let choice_str = input_popup(header, body.join("\n").as_str())?;
-        match choice_str.trim().parse::<usize>() {
-            Ok(choice) => {
-                if choice >= choices.len() {
-                    info_popup(Type::Error, "Error", "This is not a valid choice")?;
-                } else {
-                    break choice;
-                }
-            }
-            Err(_) => info_popup(
-                Type::Error,
-                "Error",
-                "This is not a valid choice. Try again.",
-            )?,
-        };
-    };
+/// Whether to protect the session file with a passphrase.
+enum ProtectChoice {
+    Passphrase,
+    Plain,
+}
 
-    choices[choice].login(client).await?;
+impl fmt::Display for ProtectChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtectChoice::Passphrase => write!(f, "Protect it with a master passphrase"),
+            ProtectChoice::Plain => write!(f, "Store it unencrypted"),
+        }
+    }
+}
 
-    Ok(())
+/// Offer the given choices to the user and login with the selected option.
+///
+/// The choices that turn out to be unavailable are removed and the others
+/// offered again.
+async fn offer_choices_and_login(
+    app: &mut App,
+    client: &Client,
+    mut choices: Vec<LoginChoice>,
+    user_id: Option<&UserId>,
+) -> anyhow::Result<(LoginMethod, Option<OidcSession>)> {
+    loop {
+        let choice = select_popup(
+            app,
+            "Choose a login method:",
+            "Several options are available to login with this homeserver.",
+            choices.clone(),
+        )
+        .await?;
+
+        if let Some(oidc) = choice.login(app, client, user_id).await? {
+            return Ok((choice.method(), oidc));
+        }
+
+        choices.retain(|other| other.to_string() != choice.to_string());
+    }
 }
 
 /// Login with a username and password.
-async fn login_with_password(client: &Client) -> anyhow::Result<()> {
+///
+/// The username is pre-filled with `user_id` if it is known.
+async fn login_with_password(
+    app: &mut App,
+    client: &Client,
+    user_id: Option<&UserId>,
+) -> anyhow::Result<()> {
     let body = "Logging in with username and password…";
+    let mut username = user_id.map(ToString::to_string).unwrap_or_default();
+    // The initial device name when logging in with a device for the first time.
+    let device_name = app.config().device_display_name.clone();
 
     loop {
         let header = "Username:";
-        let username = input_popup(header, body)?.trim().to_owned();
+        username = prefilled_input_popup(app, header, body, &username)
+            .await?
+            .trim()
+            .to_owned();
 
         let header = "Password:";
-        let password = input_popup(header, body)?.trim().to_owned();
+        let password = secret_input_popup(app, header, body).await?;
 
-        match client
-            .matrix_auth()
-            .login_username(&username, &password)
-            .initial_device_display_name(INITIAL_DEVICE_DISPLAY_NAME)
-            .await
-        {
-            Ok(_) => {
+        match login_username(client, app, &username, &password, &device_name).await? {
+            Ok(()) => {
                 info_popup(
+                    app,
                     Type::Informaton,
                     "Login successful!",
                     format!("Logged in as {username}").as_str(),
-                )?;
-                break;
+                )
+                .await?;
+                return Ok(());
+            }
+            Err(error) => {
+                info_popup(app, Type::Error, "Error", error.to_string().as_str()).await?;
             }
-            Err(_error) => info_popup(Type::Error, "Error", "Please try again.")?,
         }
     }
+}
 
-    Ok(())
+/// How to wait while the homeserver rate-limits the login.
+trait RateLimitWait {
+    async fn wait(&mut self, delay: Duration) -> anyhow::Result<()>;
+}
+
+impl RateLimitWait for App {
+    async fn wait(&mut self, delay: Duration) -> anyhow::Result<()> {
+        wait_rate_limit(self, delay).await
+    }
+}
+
+/// Log in with the username and password.
+///
+/// We retry with the same credentials as long as we are rate-limited, after
+/// waiting with `waiter`. The outer error is a failure to wait.
+async fn login_username(
+    client: &Client,
+    waiter: &mut impl RateLimitWait,
+    username: &str,
+    password: &str,
+    device_name: &str,
+) -> anyhow::Result<Result<(), LoginError>> {
+    loop {
+        let result = client
+            .matrix_auth()
+            .login_username(username, password)
+            .initial_device_display_name(device_name)
+            .request_refresh_token()
+            .await;
+
+        match result {
+            Ok(_) => return Ok(Ok(())),
+            Err(error) => match LoginError::from(&error) {
+                LoginError::RateLimited {
+                    retry_after: Some(delay),
+                } => waiter.wait(delay).await?,
+                error => return Ok(Err(error)),
+            },
+        }
+    }
+}
+
+/// Show a countdown until the rate limit is lifted.
+async fn wait_rate_limit(app: &mut App, delay: Duration) -> anyhow::Result<()> {
+    let deadline = Instant::now() + delay;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+
+        app.set_screen(
+            Screen::Login,
+            format!(
+                "Too many attempts, retrying in {} seconds…",
+                remaining.as_secs_f32().ceil()
+            ),
+        )?;
+        time::sleep(remaining.min(Duration::from_secs(1))).await;
+    }
+}
+
+/// Login with an existing access token, like the ones of service accounts.
+async fn login_with_access_token(app: &mut App, client: &Client) -> anyhow::Result<()> {
+    let body = "Logging in with an existing access token…";
+
+    loop {
+        let access_token = secret_input_popup(app, "Access token:", body).await?;
+
+        // We check the token before restoring the session, because a wrong
+        // session can't be removed from the store afterwards.
+        app.set_screen(Screen::Login, "Checking the access token…")?;
+        let WhoAmI { user_id, device_id } = match whoami(client, &access_token).await {
+            Ok(whoami) => whoami,
+            Err(error) => {
+                info_popup(app, Type::Error, "Error", error.to_string().as_str()).await?;
+                continue;
+            }
+        };
+
+        // Older homeservers don't tell us the device of the token.
+        let device_id = match device_id {
+            Some(device_id) => device_id,
+            None => loop {
+                let device_id =
+                    input_popup(app, "Device ID:", "The device ID of this access token.").await?;
+                if !device_id.trim().is_empty() {
+                    break device_id.trim().into();
+                }
+                info_popup(app, Type::Error, "Error", "The device ID can't be empty.").await?;
+            },
+        };
+
+        client
+            .restore_session(MatrixSession {
+                meta: SessionMeta {
+                    user_id: user_id.clone(),
+                    device_id,
+                },
+                tokens: MatrixSessionTokens {
+                    access_token: access_token.to_string(),
+                    refresh_token: None,
+                },
+            })
+            .await?;
+
+        info_popup(
+            app,
+            Type::Informaton,
+            "Login successful!",
+            format!("Logged in as {user_id}").as_str(),
+        )
+        .await?;
+        return Ok(());
+    }
+}
+
+/// The owner of an access token.
+#[derive(Debug)]
+pub(super) struct WhoAmI {
+    pub user_id: OwnedUserId,
+    pub device_id: Option<OwnedDeviceId>,
+}
+
+/// Ask the homeserver who owns the given access token.
+///
+/// The token is checked with a throwaway client, because a wrong session
+/// can't be removed from the store of `client` afterwards.
+pub(super) async fn whoami(client: &Client, access_token: &str) -> anyhow::Result<WhoAmI> {
+    let probe = Client::builder()
+        .homeserver_url(client.homeserver())
+        .request_config(request_config())
+        .build()
+        .await?;
+    // Only the access token is sent, the owner is what we are asking for.
+    probe
+        .restore_session(MatrixSession {
+            meta: SessionMeta {
+                user_id: "@whoami:localhost".try_into()?,
+                device_id: "WHOAMI".into(),
+            },
+            tokens: MatrixSessionTokens {
+                access_token: access_token.to_owned(),
+                refresh_token: None,
+            },
+        })
+        .await?;
+
+    let response = probe.whoami().await.map_err(|error| {
+        let error = matrix_sdk::Error::from(error);
+        match error.client_api_error_kind() {
+            Some(ErrorKind::UnknownToken { .. }) => {
+                anyhow!("The homeserver rejected this access token.")
+            }
+            _ => LoginError::from(&error).into(),
+        }
+    })?;
+
+    Ok(WhoAmI {
+        user_id: response.user_id,
+        device_id: response.device_id,
+    })
+}
+
+/// Log in again with SSO after a soft logout, the way the session was logged
+/// in, keeping its device.
+pub async fn login_again_with_sso(
+    app: &mut App,
+    client: &Client,
+    method: &LoginMethod,
+    device_id: &DeviceId,
+) -> anyhow::Result<()> {
+    match method {
+        LoginMethod::Sso { idp } => {
+            login_with_sso(app, client, idp.as_deref(), Some(device_id)).await
+        }
+        LoginMethod::SsoToken => login_with_sso_token(app, client, None, Some(device_id)).await,
+        _ => bail!("This account didn't log in with SSO."),
+    }
 }
-/// Login with SSO.
-async fn login_with_sso(client: &Client, idp: Option<&IdentityProvider>) -> anyhow::Result<()> {
-    println!("Logging in with SSO…");
 
+/// Login with SSO, through the identity provider with the ID `idp` if any.
+///
+/// A `device_id` is given to log in again with the same device, after a soft
+/// logout.
+async fn login_with_sso(
+    app: &mut App,
+    client: &Client,
+    idp: Option<&str>,
+    device_id: Option<&DeviceId>,
+) -> anyhow::Result<()> {
+    // Without a browser the local redirect server would wait forever.
+    if is_headless() {
+        return login_with_sso_token(app, client, idp, device_id).await;
+    }
+
+    app.set_screen(Screen::Login, "Logging in with SSO…")?;
+    let device_name = app.config().device_display_name.clone();
+
+    let sso_app = &mut *app;
     let mut login_builder = client.matrix_auth().login_sso(|url| async move {
         open::that(&url)?;
 
-        println!("\nOpen this URL in your browser: {url}\n");
-        println!("Waiting for login token…");
+        sso_app
+            .set_screen(
+                Screen::Login,
+                format!("Open this URL in your browser: {url}\n\nWaiting for login token…"),
+            )
+            .map_err(|err| matrix_sdk::Error::UnknownError(err.into()))?;
         Ok(())
     });
 
+    login_builder = login_builder
+        .initial_device_display_name(&device_name)
+        .request_refresh_token();
     if let Some(idp) = idp {
-        login_builder = login_builder.identity_provider_id(&idp.id);
+        login_builder = login_builder.identity_provider_id(idp);
+    }
+    if let Some(device_id) = device_id {
+        login_builder = login_builder.device_id(device_id.as_str());
     }
 
     let _response = login_builder.send().await?;
     // auth.restore_session((&response).into()).await?;
 
-    println!("Logged in as {}", client.user_id().unwrap());
+    app.set_screen(
+        Screen::Login,
+        format!("Logged in as {}", client.user_id().unwrap()),
+    )?;
 
     Ok(())
 }
+
+/// Login with SSO, by pasting the login token ourselves.
+///
+/// This is used when no browser can be opened, for example over SSH.
+async fn login_with_sso_token(
+    app: &mut App,
+    client: &Client,
+    idp: Option<&str>,
+    device_id: Option<&DeviceId>,
+) -> anyhow::Result<()> {
+    let matrix_auth = client.matrix_auth();
+    let url = matrix_auth.get_sso_login_url(SSO_REDIRECT_URL, idp).await?;
+    let device_name = app.config().device_display_name.clone();
+
+    let body = format!(
+        "Open this URL in a browser: {url}\n\n\
+         After logging in, paste the URL you were redirected to, or its loginToken."
+    );
+
+    loop {
+        let input = input_popup(app, "Login token:", &body).await?;
+        let Some(token) = parse_login_token(input.trim()) else {
+            info_popup(
+                app,
+                Type::Error,
+                "Error",
+                "No login token found, please try again.",
+            )
+            .await?;
+            continue;
+        };
+
+        let mut login_builder = matrix_auth
+            .login_token(&token)
+            .initial_device_display_name(&device_name)
+            .request_refresh_token();
+        if let Some(device_id) = device_id {
+            login_builder = login_builder.device_id(device_id.as_str());
+        }
+
+        match login_builder.await {
+            Ok(_) => {
+                app.set_screen(
+                    Screen::Login,
+                    format!("Logged in as {}", client.user_id().unwrap()),
+                )?;
+                return Ok(());
+            }
+            Err(error) => {
+                let message = token_error_message(&LoginError::from(&error));
+                info_popup(app, Type::Error, "Error", &message).await?;
+            }
+        }
+    }
+}
+
+/// The message shown when logging in with a login token failed.
+///
+/// The token is pasted again after each error, so nothing is retried for the
+/// user.
+fn token_error_message(error: &LoginError) -> String {
+    match error {
+        LoginError::WrongCredentials | LoginError::UnknownUser => {
+            "The login token is invalid or expired, please log in again in the browser.".to_owned()
+        }
+        LoginError::RateLimited {
+            retry_after: Some(delay),
+        } => format!(
+            "Too many attempts, please wait {} seconds before trying again.",
+            delay.as_secs_f32().ceil()
+        ),
+        error => error.to_string(),
+    }
+}
+
+/// Get the login token from the pasted redirect URL, or the token itself.
+fn parse_login_token(input: &str) -> Option<String> {
+    if input.is_empty() {
+        return None;
+    }
+
+    match Url::parse(input) {
+        Ok(url) => url
+            .query_pairs()
+            .find(|(key, _)| key == "loginToken")
+            .map(|(_, token)| token.into_owned()),
+        Err(_) => Some(input.to_owned()),
+    }
+}
+
+/// Whether we are unable to open a browser, like in an SSH session.
+fn is_headless() -> bool {
+    let is_set = |var| std::env::var_os(var).is_some();
+
+    is_set("SSH_CONNECTION")
+        || is_set("SSH_TTY")
+        || (cfg!(target_os = "linux") && !is_set("DISPLAY") && !is_set("WAYLAND_DISPLAY"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, ResponseTemplate,
+    };
+
+    use super::{login_username, token_error_message, whoami, RateLimitWait};
+    use crate::{
+        login::error::LoginError,
+        test_utils::{
+            client, error_response, login_response, mock_homeserver, rate_limited_response,
+        },
+    };
+
+    /// Records the delays instead of waiting.
+    #[derive(Default)]
+    struct Delays(Vec<Duration>);
+
+    impl RateLimitWait for Delays {
+        async fn wait(&mut self, delay: Duration) -> anyhow::Result<()> {
+            self.0.push(delay);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_while_rate_limited() {
+        let server = mock_homeserver().await;
+        // The SDK doesn't retry, so we wait once for each of them.
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/login"))
+            .respond_with(rate_limited_response(10))
+            .up_to_n_times(10)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/login"))
+            .respond_with(login_response())
+            .mount(&server)
+            .await;
+
+        let client = client(&server).await;
+        let mut delays = Delays::default();
+        let result = login_username(&client, &mut delays, "alice", "password", "test")
+            .await
+            .unwrap();
+
+        assert!(result.is_ok(), "{result:?}");
+        assert_eq!(client.user_id().unwrap(), "@alice:example.org");
+        assert_eq!(delays.0, [Duration::from_millis(10); 10]);
+    }
+
+    #[tokio::test]
+    async fn stops_on_other_errors() {
+        let server = mock_homeserver().await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/login"))
+            .respond_with(error_response(403, "M_FORBIDDEN"))
+            .mount(&server)
+            .await;
+
+        let client = client(&server).await;
+        let mut delays = Delays::default();
+        let result = login_username(&client, &mut delays, "alice", "wrong", "test")
+            .await
+            .unwrap();
+
+        assert!(
+            matches!(result, Err(LoginError::WrongCredentials)),
+            "{result:?}"
+        );
+        assert!(delays.0.is_empty());
+    }
+
+    #[test]
+    fn token_errors_are_about_the_token() {
+        let message = token_error_message(&LoginError::WrongCredentials);
+        assert!(message.contains("login token"), "{message}");
+
+        // The token has to be pasted again, so no retry is promised.
+        let message = token_error_message(&LoginError::RateLimited {
+            retry_after: Some(Duration::from_millis(2500)),
+        });
+        assert_eq!(
+            message,
+            "Too many attempts, please wait 3 seconds before trying again."
+        );
+    }
+
+    #[tokio::test]
+    async fn whoami_gives_the_owner_of_the_token() {
+        let server = mock_homeserver().await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/account/whoami"))
+            .and(header("authorization", "Bearer access_token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": "@alice:example.org",
+                "device_id": "DEVICEID",
+            })))
+            .mount(&server)
+            .await;
+
+        let client = client(&server).await;
+        let owner = whoami(&client, "access_token").await.unwrap();
+
+        assert_eq!(owner.user_id, "@alice:example.org");
+        assert_eq!(owner.device_id.unwrap(), "DEVICEID");
+        // The client we log in with is left untouched.
+        assert!(client.user_id().is_none());
+    }
+
+    #[tokio::test]
+    async fn whoami_classifies_the_errors() {
+        let server = mock_homeserver().await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/account/whoami"))
+            .respond_with(rate_limited_response(10))
+            .mount(&server)
+            .await;
+
+        let error = whoami(&client(&server).await, "access_token")
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(
+                error.downcast_ref::<LoginError>(),
+                Some(LoginError::RateLimited { .. })
+            ),
+            "{error:?}"
+        );
+    }
+}
@@ -1,36 +1,124 @@
-mod login_new;
+mod error;
+pub mod lock;
+pub mod login_new;
+pub mod oidc;
 pub mod persist_session;
+pub mod profile;
+mod register;
+pub mod session_file;
+pub mod stores;
+pub mod sync_state;
 
 use crate::login::login_new::login_new;
-use matrix_sdk::{self, Client};
-use std::path::{Path, PathBuf};
-use tokio::fs;
+use crate::ui_elements::app::App;
+use anyhow::bail;
+use matrix_sdk::{self, ruma::OwnedUserId, Client};
+use std::path::PathBuf;
 
-use self::persist_session::{restore_session, FullSession};
+use self::error::SessionError;
+use self::lock::{is_locked, ProfileLock};
+use self::persist_session::{recover_session, restore_session};
+use self::profile::pick_profile;
+use self::session_file::SessionFile;
+use self::stores::register_store;
+
+/// The folder containing this example's data, unless another one is given.
+pub fn default_data_dir() -> PathBuf {
+    dirs::data_dir()
+        .expect("no data_dir directory found")
+        .join("persist_session")
+}
+
+/// What to log in with, when given instead of asked.
+#[derive(Debug, Clone)]
+pub struct LoginOptions {
+    /// The folder containing the profiles and their stores.
+    pub data_dir: PathBuf,
+
+    /// The profile to use.
+    pub profile: Option<String>,
+
+    /// The URL of the homeserver, so it isn't discovered.
+    pub homeserver: Option<String>,
+
+    /// The Matrix ID to log in with.
+    pub user: Option<OwnedUserId>,
+}
+
+impl LoginOptions {
+    /// Ask for everything, with the profiles in `data_dir`.
+    pub fn new(data_dir: PathBuf) -> Self {
+        Self {
+            data_dir,
+            profile: None,
+            homeserver: None,
+            user: None,
+        }
+    }
+}
 
 /// Restoring a session with encryption without having a persisted store
 /// will break the encryption setup and the client will not be able to send or
 /// receive encrypted messages, hence the need to persist the session.
 ///
-/// To reset the login, simply delete the folder containing the session
-/// file, the location is shown in the logs. Note that the database must be
-/// deleted too as it can't be reused.
-pub async fn login() -> anyhow::Result<(Client, Option<String>, PathBuf)> {
-    // info_popup(Type::Informaton, "Informaton", "body")?;
-    // info_popup(Type::Error, "Error", "Now iagine the body is very big and doesnt fit into one Line. I really wonder whats gonna happen then since I only have percentages inputted and thus am not able to ")?;
-
-    // The folder containing this example's data.
-    let data_dir = dirs::data_dir()
-        .expect("no data_dir directory found")
-        .join("persist_session");
+/// Each profile has its own session file and store, the profiles whose
+/// session file is in `open` are already logged in and not offered.
+///
+/// To reset the login of a profile, log out from the room list. This removes
+/// the session file and the database too, as it can't be reused. If the
+/// session is broken, the user is offered to log in again instead.
+///
+/// The returned lock keeps the profile for this instance, unless it is
+/// read-only because another instance uses it.
+pub async fn login(
+    app: &mut App,
+    options: &LoginOptions,
+    open: &[PathBuf],
+) -> anyhow::Result<(Client, Option<String>, SessionFile, ProfileLock)> {
+    let data_dir = &options.data_dir;
+
+    let profile = pick_profile(app, data_dir, open, options.profile.as_deref()).await?;
     // The file where the session is persisted.
-    let session_file = data_dir.join("session");
+    let session_file = SessionFile::new(profile.session_file());
+
+    if session_file.exists() {
+        match restore_session(app, &session_file).await {
+            Ok((client, sync_token, lock)) => {
+                // This is only needed to find the orphaned stores, it's not
+                // worth failing the login for.
+                let _ = register_store(data_dir, &session_file).await;
+                return Ok((client, sync_token, session_file, lock));
+            }
+            Err(error) => match error.downcast_ref::<SessionError>() {
+                // The session may only look broken from here, like while the
+                // other instance rewrites it. It's up to that one to recover.
+                Some(_) if is_locked(profile.dir()) => return Err(error),
+                Some(error) => recover_session(app, &session_file, error).await?,
+                None => return Err(error),
+            },
+        }
+    }
+
+    let (client, lock) = login_new(app, profile.dir(), &session_file, options).await?;
+    let _ = register_store(data_dir, &session_file).await;
+
+    Ok((client, None, session_file, lock))
+}
+
+/// Restore the session of a profile, without offering to log in if there is
+/// none.
+pub async fn restore(
+    app: &mut App,
+    options: &LoginOptions,
+) -> anyhow::Result<(Client, SessionFile, ProfileLock)> {
+    let profile = pick_profile(app, &options.data_dir, &[], options.profile.as_deref()).await?;
+    let session_file = SessionFile::new(profile.session_file());
+
+    if !session_file.exists() {
+        bail!("The profile '{}' is not logged in.", profile.name);
+    }
 
-    let (client, sync_token) = if session_file.exists() {
-        restore_session(&session_file).await?
-    } else {
-        (login_new(&data_dir, &session_file).await?, None)
-    };
+    let (client, _, lock) = restore_session(app, &session_file).await?;
 
-    Ok((client, sync_token, session_file))
+    Ok((client, session_file, lock))
 }
@@ -0,0 +1,671 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use crossterm::event::KeyCode;
+use matrix_sdk::{
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    reqwest::{self, header::CONTENT_TYPE, Url},
+    ruma::DeviceId,
+    Client, SessionMeta,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+    time,
+};
+
+use crate::{
+    login::{
+        login_new::{whoami, WhoAmI},
+        persist_session::persist_session_tokens,
+        session_file::SessionFile,
+    },
+    ui_elements::app::{App, Screen},
+};
+
+/// Where the homeserver tells us which OpenID Connect provider it delegates
+/// its authentication to, see MSC2965.
+const AUTH_ISSUER_PATH: &str = "_matrix/client/unstable/org.matrix.msc2965/auth_issuer";
+
+/// The name of the client, shown by the provider when asking for consent.
+const CLIENT_NAME: &str = "matrix-client";
+
+/// The scope to access the whole client-server API, see MSC2967.
+const API_SCOPE: &str = "urn:matrix:org.matrix.msc2967.client:api:*";
+
+/// The prefix of the scope requesting a device ID, see MSC2967.
+const DEVICE_SCOPE_PREFIX: &str = "urn:matrix:org.matrix.msc2967.client:device:";
+
+/// How long access tokens live when the provider doesn't say it. Providers
+/// commonly use 5 minutes.
+pub const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// How long the browser has to send its request once connected. Browsers
+/// open connections in advance, which may never be used.
+const REDIRECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The page shown in the browser once it was redirected to us.
+const REDIRECT_RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
+    Content-Type: text/plain; charset=utf-8\r\n\
+    Connection: close\r\n\r\n\
+    You can now close this tab and go back to the client.";
+
+/// The data needed to refresh the tokens of an OpenID Connect session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcSession {
+    /// The URL of the provider.
+    pub issuer: String,
+
+    /// The endpoint of the provider to get new tokens.
+    pub token_endpoint: String,
+
+    /// The ID this client got when it registered with the provider.
+    pub client_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthIssuer {
+    issuer: String,
+}
+
+/// The parts of the provider metadata we use.
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    registration_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientRegistration {
+    client_id: String,
+}
+
+/// The tokens returned by the provider.
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+
+    /// How long the access token is valid, in seconds.
+    pub expires_in: Option<u64>,
+}
+
+/// Get the OpenID Connect provider the homeserver delegates its
+/// authentication to, if any.
+pub async fn discover_issuer(client: &Client) -> Option<String> {
+    let url = client.homeserver().join(AUTH_ISSUER_PATH).ok()?;
+    let response = reqwest::get(url).await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+
+    let AuthIssuer { issuer } = serde_json::from_str(&response.text().await.ok()?).ok()?;
+    Some(issuer)
+}
+
+/// Login with the given OpenID Connect provider.
+///
+/// This uses the authorization code grant with PKCE, the browser being
+/// redirected to a local server. The returned session must be persisted to
+/// refresh the tokens later.
+///
+/// A `device_id` is given to log in again with the same device, after a soft
+/// logout.
+pub async fn login_with_oidc(
+    app: &mut App,
+    client: &Client,
+    issuer: &str,
+    device_id: Option<&DeviceId>,
+) -> anyhow::Result<OidcSession> {
+    app.set_screen(Screen::Login, "Contacting the authentication provider…")?;
+    let metadata = provider_metadata(issuer).await?;
+
+    // The provider redirects the browser to this local server once the user
+    // logged in.
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let redirect_uri = format!("http://127.0.0.1:{}/", listener.local_addr()?.port());
+
+    let client_id = register_client(&metadata, &redirect_uri).await?;
+
+    let code_verifier = random_string(64);
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+    let state = random_string(16);
+    // With OpenID Connect, the client chooses its device ID.
+    let device_id = device_id.map_or_else(|| random_string(10).to_uppercase(), ToString::to_string);
+
+    let mut url = Url::parse(&metadata.authorization_endpoint)?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &client_id)
+        .append_pair("redirect_uri", &redirect_uri)
+        .append_pair(
+            "scope",
+            &format!("{API_SCOPE} {DEVICE_SCOPE_PREFIX}{device_id}"),
+        )
+        .append_pair("state", &state)
+        .append_pair("code_challenge", &code_challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    // The URL is shown too in case the browser doesn't open.
+    let _ = open::that(url.as_str());
+    app.set_screen(
+        Screen::Login,
+        format!(
+            "Open this URL in your browser: {url}\n\nWaiting for the login… (Escape to cancel)"
+        ),
+    )?;
+
+    // Each connection is read in its own task, so one that stays silent
+    // doesn't hold the others, nor the Escape key.
+    let mut connections = JoinSet::new();
+    let code = loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                // Anything can connect to the local server, a failing
+                // connection doesn't end the login.
+                if let Ok((stream, _)) = accepted {
+                    let state = state.clone();
+                    connections.spawn(async move {
+                        time::timeout(REDIRECT_TIMEOUT, receive_code(stream, &state)).await
+                    });
+                }
+            }
+            Some(received) = connections.join_next() => {
+                if let Ok(Ok(Ok(Some(code)))) = received {
+                    break code?;
+                }
+            }
+            key = app.next_key() => {
+                if key?.is_some_and(|key| key.code == KeyCode::Esc) {
+                    bail!("Exited.");
+                }
+            }
+        }
+    };
+
+    app.set_screen(Screen::Login, "Getting the access token…")?;
+    let tokens = request_tokens(
+        &metadata.token_endpoint,
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &redirect_uri),
+            ("client_id", &client_id),
+            ("code_verifier", &code_verifier),
+        ],
+    )
+    .await?;
+
+    let WhoAmI { user_id, .. } = whoami(client, &tokens.access_token).await?;
+    let tokens = MatrixSessionTokens {
+        access_token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+    };
+    match client.session_meta() {
+        // Logging in again after a soft logout, the client keeps its session.
+        Some(meta) => {
+            if meta.user_id != user_id {
+                bail!("Logged in as {user_id} instead of {}.", meta.user_id);
+            }
+            set_tokens(client, tokens)?;
+        }
+        None => {
+            client
+                .restore_session(MatrixSession {
+                    meta: SessionMeta {
+                        user_id,
+                        device_id: device_id.into(),
+                    },
+                    tokens,
+                })
+                .await?
+        }
+    }
+
+    Ok(OidcSession {
+        issuer: issuer.to_owned(),
+        token_endpoint: metadata.token_endpoint,
+        client_id,
+    })
+}
+
+/// Get new tokens with the given refresh token.
+pub async fn refresh_tokens(
+    oidc: &OidcSession,
+    refresh_token: &str,
+) -> anyhow::Result<TokenResponse> {
+    request_tokens(
+        &oidc.token_endpoint,
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", &oidc.client_id),
+        ],
+    )
+    .await
+}
+
+/// Refresh the tokens of the OpenID Connect session of the client with the
+/// provider, and persist them.
+///
+/// The SDK only refreshes tokens with the Matrix `/refresh` endpoint, which
+/// homeservers delegating their authentication don't offer.
+///
+/// Returns how long the new access token is valid.
+pub async fn refresh_session(
+    client: &Client,
+    session_file: &SessionFile,
+) -> anyhow::Result<Duration> {
+    let full_session = session_file.read().await?;
+    let (Some(oidc), Some(refresh_token)) = (
+        &full_session.oidc,
+        full_session.user_session.tokens.refresh_token,
+    ) else {
+        bail!("This session can't be refreshed.");
+    };
+
+    let response = refresh_tokens(oidc, &refresh_token).await?;
+    let expires_in = response
+        .expires_in
+        .map_or(DEFAULT_TOKEN_LIFETIME, Duration::from_secs);
+    let tokens = MatrixSessionTokens {
+        access_token: response.access_token,
+        // Providers may keep the same refresh token.
+        refresh_token: response.refresh_token.or(Some(refresh_token)),
+    };
+
+    set_tokens(client, tokens.clone())?;
+    persist_session_tokens(session_file, tokens).await?;
+
+    Ok(expires_in)
+}
+
+/// Use new tokens in the client, keeping its session.
+///
+/// Restoring the session again would set up the store and the encryption of
+/// the running client a second time.
+fn set_tokens(client: &Client, tokens: MatrixSessionTokens) -> anyhow::Result<()> {
+    if client.session_meta().is_none() {
+        bail!("The client has no session.");
+    }
+    client.matrix_auth().set_session_tokens(tokens);
+
+    Ok(())
+}
+
+/// Get the metadata of the provider.
+async fn provider_metadata(issuer: &str) -> anyhow::Result<ProviderMetadata> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    let response = reqwest::get(url).await?.error_for_status()?;
+
+    Ok(serde_json::from_str(&response.text().await?)?)
+}
+
+/// Register this client with the provider and return its ID.
+async fn register_client(
+    metadata: &ProviderMetadata,
+    redirect_uri: &str,
+) -> anyhow::Result<String> {
+    let registration_endpoint = metadata
+        .registration_endpoint
+        .as_deref()
+        .ok_or_else(|| anyhow!("The authentication provider doesn't allow registering clients."))?;
+
+    let body = json!({
+        "client_name": CLIENT_NAME,
+        "client_uri": "https://github.com/PaulHerk/matrix-client",
+        "application_type": "native",
+        "redirect_uris": [redirect_uri],
+        "grant_types": ["authorization_code", "refresh_token"],
+        "response_types": ["code"],
+        "token_endpoint_auth_method": "none",
+    });
+    let response = reqwest::Client::new()
+        .post(registration_endpoint)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let ClientRegistration { client_id } = serde_json::from_str(&response.text().await?)?;
+    Ok(client_id)
+}
+
+/// Send a request to the token endpoint of the provider.
+async fn request_tokens(
+    token_endpoint: &str,
+    params: &[(&str, &str)],
+) -> anyhow::Result<TokenResponse> {
+    let response = reqwest::Client::new()
+        .post(token_endpoint)
+        .form(params)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        bail!(
+            "The authentication provider refused to give a token: {}",
+            response.text().await?
+        );
+    }
+
+    Ok(serde_json::from_str(&response.text().await?)?)
+}
+
+/// Read the redirect request of the browser and return the answer of the
+/// provider, if it is the one we expect.
+///
+/// The outer error is a failing connection, the inner one an error of the
+/// provider.
+async fn receive_code(
+    mut stream: TcpStream,
+    state: &str,
+) -> anyhow::Result<Option<anyhow::Result<String>>> {
+    let mut buf = vec![0; 8192];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+
+    // The request line looks like `GET /?code=…&state=… HTTP/1.1`.
+    let Some(path) = request
+        .lines()
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+    else {
+        return Ok(None);
+    };
+    let url = Url::parse(&format!("http://127.0.0.1{path}"))?;
+
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+
+    // Browsers also ask for a favicon, ignore anything that isn't for us.
+    if param("state").as_deref() != Some(state) {
+        return Ok(None);
+    }
+
+    // The answer of the provider is in the URL, the page is only for the
+    // user.
+    let _ = stream.write_all(REDIRECT_RESPONSE.as_bytes()).await;
+
+    if let Some(error) = param("error") {
+        let description = param("error_description").unwrap_or_default();
+        return Ok(Some(Err(anyhow!(
+            "The authentication provider returned an error: {error} {description}"
+        ))));
+    }
+
+    Ok(Some(param("code").ok_or_else(|| {
+        anyhow!("The authentication provider didn't return a code.")
+    })))
+}
+
+fn random_string(len: usize) -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::{
+        matrix_auth::{MatrixSession, MatrixSessionTokens},
+        SessionMeta,
+    };
+    use serde_json::json;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+    use wiremock::{
+        matchers::{body_string_contains, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{
+        discover_issuer, provider_metadata, receive_code, refresh_session, refresh_tokens,
+        register_client, OidcSession, DEFAULT_TOKEN_LIFETIME,
+    };
+    use crate::{
+        login::{
+            persist_session::{FullSession, LoginMethod},
+            session_file::SessionFile,
+        },
+        test_utils::{client, mock_homeserver, temp_path},
+    };
+
+    /// A stub provider, with its metadata and a client registration endpoint.
+    async fn stub_issuer() -> MockServer {
+        let issuer = MockServer::start().await;
+        let uri = issuer.uri();
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "issuer": uri,
+                "authorization_endpoint": format!("{uri}/authorize"),
+                "token_endpoint": format!("{uri}/token"),
+                "registration_endpoint": format!("{uri}/register"),
+            })))
+            .mount(&issuer)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/register"))
+            .and(body_string_contains(
+                "\"token_endpoint_auth_method\":\"none\"",
+            ))
+            .respond_with(ResponseTemplate::new(201).set_body_json(json!({
+                "client_id": "client",
+            })))
+            .mount(&issuer)
+            .await;
+
+        issuer
+    }
+
+    fn oidc_session(issuer: &MockServer) -> OidcSession {
+        OidcSession {
+            issuer: issuer.uri(),
+            token_endpoint: format!("{}/token", issuer.uri()),
+            client_id: "client".to_owned(),
+        }
+    }
+
+    fn user_session(access_token: &str, refresh_token: &str) -> MatrixSession {
+        MatrixSession {
+            meta: SessionMeta {
+                user_id: "@alice:example.org".try_into().unwrap(),
+                device_id: "DEVICEID".into(),
+            },
+            tokens: MatrixSessionTokens {
+                access_token: access_token.to_owned(),
+                refresh_token: Some(refresh_token.to_owned()),
+            },
+        }
+    }
+
+    /// Mount a token endpoint exchanging `refresh_token` for `response`.
+    async fn mount_token_endpoint(
+        issuer: &MockServer,
+        refresh_token: &str,
+        response: ResponseTemplate,
+    ) {
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .and(body_string_contains(format!(
+                "refresh_token={refresh_token}"
+            )))
+            .and(body_string_contains("client_id=client"))
+            .respond_with(response)
+            .mount(issuer)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn discovers_the_issuer_of_the_homeserver() {
+        let homeserver = mock_homeserver().await;
+        let client = client(&homeserver).await;
+        assert_eq!(discover_issuer(&client).await, None);
+
+        Mock::given(method("GET"))
+            .and(path(
+                "/_matrix/client/unstable/org.matrix.msc2965/auth_issuer",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "issuer": "https://auth.example.org/",
+            })))
+            .mount(&homeserver)
+            .await;
+        assert_eq!(
+            discover_issuer(&client).await.as_deref(),
+            Some("https://auth.example.org/")
+        );
+    }
+
+    #[tokio::test]
+    async fn registers_with_the_provider() {
+        let issuer = stub_issuer().await;
+
+        let metadata = provider_metadata(&issuer.uri()).await.unwrap();
+        assert_eq!(metadata.token_endpoint, format!("{}/token", issuer.uri()));
+
+        let client_id = register_client(&metadata, "http://127.0.0.1:1234/")
+            .await
+            .unwrap();
+        assert_eq!(client_id, "client");
+    }
+
+    #[tokio::test]
+    async fn refreshes_tokens() {
+        let issuer = stub_issuer().await;
+        mount_token_endpoint(
+            &issuer,
+            "refresh",
+            ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "new_access",
+                "refresh_token": "new_refresh",
+                "expires_in": 60,
+            })),
+        )
+        .await;
+
+        let tokens = refresh_tokens(&oidc_session(&issuer), "refresh")
+            .await
+            .unwrap();
+        assert_eq!(tokens.access_token, "new_access");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("new_refresh"));
+        assert_eq!(tokens.expires_in, Some(60));
+    }
+
+    #[tokio::test]
+    async fn refused_refresh_is_an_error() {
+        let issuer = stub_issuer().await;
+        mount_token_endpoint(
+            &issuer,
+            "revoked",
+            ResponseTemplate::new(400).set_body_json(json!({ "error": "invalid_grant" })),
+        )
+        .await;
+
+        let error = refresh_tokens(&oidc_session(&issuer), "revoked")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("invalid_grant"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn refreshes_the_session_of_the_client() {
+        let issuer = stub_issuer().await;
+        // The provider keeps the same refresh token.
+        mount_token_endpoint(
+            &issuer,
+            "refresh",
+            ResponseTemplate::new(200).set_body_json(json!({ "access_token": "new_access" })),
+        )
+        .await;
+
+        let homeserver = mock_homeserver().await;
+        let client = client(&homeserver).await;
+        client
+            .restore_session(user_session("old_access", "refresh"))
+            .await
+            .unwrap();
+
+        let session_file = SessionFile::new(temp_path("oidc-session"));
+        let client_session = serde_json::from_value(json!({
+            "homeserver": homeserver.uri(),
+            "db_path": temp_path("oidc-store"),
+            "passphrase": "passphrase",
+        }))
+        .unwrap();
+        session_file
+            .write(&FullSession {
+                client_session,
+                user_session: user_session("old_access", "refresh"),
+                sync_token: None,
+                oidc: Some(oidc_session(&issuer)),
+                login_method: LoginMethod::Oidc,
+            })
+            .await
+            .unwrap();
+
+        let expires_in = refresh_session(&client, &session_file).await;
+        let persisted = session_file.read().await;
+        let _ = std::fs::remove_file(session_file.path());
+
+        assert_eq!(expires_in.unwrap(), DEFAULT_TOKEN_LIFETIME);
+        assert_eq!(client.access_token().as_deref(), Some("new_access"));
+        let tokens = persisted.unwrap().user_session.tokens;
+        assert_eq!(tokens.access_token, "new_access");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("refresh"));
+    }
+
+    /// Send `request` to the local server, like the browser does.
+    async fn receive(request: &str) -> anyhow::Result<Option<anyhow::Result<String>>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut browser = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        browser.write_all(request.as_bytes()).await.unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        receive_code(stream, "state").await
+    }
+
+    #[tokio::test]
+    async fn receives_the_answer_of_the_provider() {
+        let code = receive("GET /?code=code&state=state HTTP/1.1\r\n\r\n").await;
+        assert_eq!(code.unwrap().unwrap().unwrap(), "code");
+
+        let error = receive("GET /?error=access_denied&state=state HTTP/1.1\r\n\r\n").await;
+        assert!(error.unwrap().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn ignores_other_requests() {
+        let favicon = receive("GET /favicon.ico HTTP/1.1\r\n\r\n").await;
+        assert!(favicon.unwrap().is_none());
+
+        let other_state = receive("GET /?code=code&state=other HTTP/1.1\r\n\r\n").await;
+        assert!(other_state.unwrap().is_none());
+
+        let garbage = receive("\r\n").await;
+        assert!(garbage.unwrap().is_none());
+    }
+}
@@ -1,13 +1,35 @@
-use matrix_sdk::{self, Client};
+use matrix_sdk::{self, config::RequestConfig, Client, ClientBuilder};
 
-use std::path::{Path, PathBuf};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
 
-use matrix_sdk::matrix_auth::MatrixSession;
+use anyhow::bail;
+
+use matrix_sdk::{
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    ruma::{api::client::error::ErrorKind, OwnedUserId, UserId},
+};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 
-use crate::ui_elements::input_popup::input_popup;
+use crate::{
+    login::{
+        error::SessionError,
+        lock::{lock_profile, ProfileLock},
+        oidc::{discover_issuer, refresh_tokens, OidcSession},
+        session_file::SessionFile,
+        sync_state, LoginOptions,
+    },
+    ui_elements::{
+        app::{App, Screen},
+        info_popup::{info_popup, Type},
+        input_popup::{input_popup, secret_input_popup},
+        select_popup::select_popup,
+    },
+};
 
 /// The data needed to re-build a client.
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,6 +44,39 @@ pub struct ClientSession {
     passphrase: String,
 }
 
+impl ClientSession {
+    /// The path of the database.
+    pub fn db_path(&self) -> &Path {
+        &self.db_path
+    }
+
+    /// A builder of the client of this session.
+    ///
+    /// With `handle_refresh_tokens`, the SDK refreshes the access token when
+    /// it expires, we persist the new one when notified. It can't refresh
+    /// OpenID Connect sessions, the account does it.
+    fn client_builder(&self, handle_refresh_tokens: bool) -> ClientBuilder {
+        let builder = Client::builder()
+            .homeserver_url(&self.homeserver)
+            .sqlite_store(&self.db_path, Some(&self.passphrase))
+            .request_config(request_config());
+        if handle_refresh_tokens {
+            builder.handle_refresh_tokens()
+        } else {
+            builder
+        }
+    }
+}
+
+/// How the clients send their requests.
+///
+/// The SDK doesn't retry the failed requests: the login and the sync retry
+/// themselves, after the delay asked by the homeserver, and show the errors
+/// meanwhile.
+pub fn request_config() -> RequestConfig {
+    RequestConfig::new().disable_retry()
+}
+
 /// The full session to persist.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FullSession {
@@ -31,47 +86,213 @@ pub struct FullSession {
     /// The Matrix user session.
     pub user_session: MatrixSession,
 
-    /// The latest sync token.
-    ///
-    /// It is only needed to persist it when using `Client::sync_once()` and we
-    /// want to make our syncs faster by not receiving all the initial sync
-    /// again.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// The sync token, in session files from before it was moved to the
+    /// sync state.
+    #[serde(default, skip_serializing)]
     pub sync_token: Option<String>,
+
+    /// The OpenID Connect session, if we logged in through a provider.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oidc: Option<OidcSession>,
+
+    /// How we logged in, to do it again the same way after a soft logout.
+    #[serde(default)]
+    pub login_method: LoginMethod,
+}
+
+/// How a session was logged in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LoginMethod {
+    /// With a username and a password, or by creating the account. Session
+    /// files from before the method was kept are assumed to be so.
+    #[default]
+    Password,
+
+    /// With SSO, through the identity provider with the given ID if any.
+    Sso { idp: Option<String> },
+
+    /// With SSO, pasting the login token instead of being redirected to a
+    /// local server.
+    SsoToken,
+
+    /// With an existing access token.
+    AccessToken,
+
+    /// With an OpenID Connect provider, its session is kept too.
+    Oidc,
 }
 
 /// Restore a previous session.
-pub async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<String>)> {
-    println!(
-        "Previous session found in '{}'",
-        session_file.to_string_lossy()
-    );
+///
+/// If the session file is protected by a passphrase, it is asked to unlock it.
+/// The sync token is returned too if the session file still contains one.
+///
+/// The profile of the session is locked while the client uses it. If another
+/// instance holds the lock, the session can be restored read-only, in which
+/// case nothing is written.
+pub async fn restore_session(
+    app: &mut App,
+    session_file: &SessionFile,
+) -> anyhow::Result<(Client, Option<String>, ProfileLock)> {
+    app.set_screen(
+        Screen::Login,
+        format!(
+            "Previous session found in '{}'",
+            session_file.path().to_string_lossy()
+        ),
+    )?;
+
+    let profile_dir = session_file
+        .path()
+        .parent()
+        .expect("The session file should be in a profile");
+    let lock = lock_profile(app, profile_dir, true).await?;
+
+    while session_file.is_locked().await? {
+        let passphrase = secret_input_popup(
+            app,
+            "Master passphrase:",
+            "This session is protected by a passphrase.",
+        )
+        .await?;
+
+        if let Err(error) = session_file.unlock(&passphrase).await {
+            info_popup(app, Type::Error, "Error", error.to_string().as_str()).await?;
+        }
+    }
+
+    let mut full_session = session_file.read().await?;
+
+    // Without its store, the session would lose its encryption keys.
+    let db_path = &full_session.client_session.db_path;
+    if !db_path.exists() {
+        return Err(SessionError::StoreMissing(db_path.clone()).into());
+    }
+
+    // The access tokens of OpenID Connect providers are short-lived, so we get
+    // new ones before restoring the session. When read-only, the other instance
+    // takes care of it. If the provider can't be reached, we start offline
+    // with the old tokens.
+    if let (false, Some(oidc), Some(refresh_token)) = (
+        lock.is_read_only(),
+        &full_session.oidc,
+        &full_session.user_session.tokens.refresh_token,
+    ) {
+        app.set_screen(Screen::Login, "Refreshing the access token…")?;
+        if let Ok(tokens) = refresh_tokens(oidc, refresh_token).await {
+            let session_tokens = &mut full_session.user_session.tokens;
+            session_tokens.access_token = tokens.access_token;
+            // Providers may keep the same refresh token.
+            if let Some(refresh_token) = tokens.refresh_token {
+                session_tokens.refresh_token = Some(refresh_token);
+            }
+            session_file.write(&full_session).await?;
+        }
+    }
 
-    // The session was serialized as JSON in a file.
-    let serialized_session = fs::read_to_string(session_file).await?;
     let FullSession {
         client_session,
         user_session,
         sync_token,
-    } = serde_json::from_str(&serialized_session)?;
+        oidc,
+        ..
+    } = full_session;
 
-    // Build the client with the previous settings from the session.
-    let client = Client::builder()
-        .homeserver_url(client_session.homeserver)
-        .sqlite_store(client_session.db_path, Some(&client_session.passphrase))
+    // Build the client with the previous settings from the session. When
+    // read-only, the other instance refreshes the tokens.
+    let client = client_session
+        .client_builder(!lock.is_read_only() && oidc.is_none())
         .build()
-        .await?;
+        .await
+        .map_err(|err| SessionError::StoreCorrupt(err.to_string()))?;
 
-    println!("Restoring session for {}…", user_session.meta.user_id);
+    app.set_screen(
+        Screen::Login,
+        format!("Restoring session for {}…", user_session.meta.user_id),
+    )?;
 
     // Restore the Matrix user session.
     client.restore_session(user_session).await?;
 
-    Ok((client, sync_token))
+    Ok((client, sync_token, lock))
+}
+
+enum RecoveryChoice {
+    LogInAgain,
+    Quit,
+}
+
+impl fmt::Display for RecoveryChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecoveryChoice::LogInAgain => write!(f, "Log in again"),
+            RecoveryChoice::Quit => write!(f, "Quit"),
+        }
+    }
+}
+
+/// Let the user start over when the session can't be restored.
+///
+/// The broken session file is kept next to the new one with a `.broken`
+/// extension, in case it can be fixed by hand.
+pub async fn recover_session(
+    app: &mut App,
+    session_file: &SessionFile,
+    error: &SessionError,
+) -> anyhow::Result<()> {
+    let backup = session_file.path().with_extension("broken");
+    let body = format!(
+        "{error}\n\nLogging in again creates a new session, the broken one is kept in '{}'.",
+        backup.to_string_lossy()
+    );
+
+    let choice = select_popup(
+        app,
+        "The session can't be restored",
+        &body,
+        vec![RecoveryChoice::LogInAgain, RecoveryChoice::Quit],
+    )
+    .await?;
+    if let RecoveryChoice::Quit = choice {
+        bail!("Exited.");
+    }
+
+    fs::rename(session_file.path(), backup).await?;
+    // The sync state belongs to the broken session.
+    let sync_state = sync_state::path(session_file.path());
+    if sync_state.exists() {
+        fs::remove_file(sync_state).await?;
+    }
+    // The new session is offered to be protected again.
+    session_file.set_key(None)?;
+
+    Ok(())
 }
 
 /// Build a new client.
-pub async fn build_client(data_dir: &Path) -> anyhow::Result<(Client, ClientSession)> {
+///
+/// The homeserver is discovered from the user ID when possible, which is
+/// returned too so it doesn't need to be asked again. The user ID and the
+/// homeserver URL in `options` are tried first, before asking. The OpenID
+/// Connect provider the homeserver delegates its authentication to is
+/// returned too, if any.
+///
+/// The profile in `data_dir` is locked, and stays so as long as the returned
+/// lock lives.
+pub async fn build_client(
+    app: &mut App,
+    data_dir: &Path,
+    options: &LoginOptions,
+) -> anyhow::Result<(
+    Client,
+    ClientSession,
+    Option<OwnedUserId>,
+    Option<String>,
+    ProfileLock,
+)> {
+    let lock = lock_profile(app, data_dir, false).await?;
+
     let mut rng = thread_rng();
 
     // Generating a subfolder for the database is not mandatory, but it is useful if
@@ -91,37 +312,105 @@ pub async fn build_client(data_dir: &Path) -> anyhow::Result<(Client, ClientSess
         .map(char::from)
         .collect();
 
+    // The user ID, if the homeserver was discovered from it or it was given.
+    let mut user_id = None;
+    // Whether we still try to discover the homeserver from the user ID, or ask
+    // for its URL directly.
+    let mut discover = true;
+
+    // The given values are only tried once, then they are asked.
+    let mut given_user = options.user.clone();
+    let mut given_homeserver = options.homeserver.clone();
+    if given_homeserver.is_some() {
+        discover = false;
+        user_id = given_user.take();
+    }
+
     // We create a loop here so the user can retry if an error happens.
     loop {
-        let homeserver = input_popup("Homeserver URL", "Please Input your homeserver URL here.")?;
-
-        println!("\nChecking homeserver…");
-
-        match Client::builder()
-            .homeserver_url(&homeserver)
-            // We use the SQLite store, which is enabled by default. This is the crucial part to
-            // persist the encryption setup.
-            // Note that other store backends are available and you can even implement your own.
-            .sqlite_store(&db_path, Some(&passphrase))
-            .build()
-            .await
-        {
-            Ok(client) => {
-                return Ok((
-                    client,
-                    ClientSession {
-                        homeserver,
-                        db_path,
-                        passphrase,
-                    },
-                ))
+        // The homeserver is checked without a store, the client keeping the
+        // session is built once we know how it logs in.
+        let builder = Client::builder().request_config(request_config());
+
+        let builder = if discover {
+            let id = match given_user.take() {
+                Some(id) => id,
+                None => {
+                    let input = input_popup(
+                        app,
+                        "Matrix ID",
+                        "Please Input your Matrix ID here, like @alice:example.org.",
+                    )
+                    .await?;
+
+                    let Ok(id) = UserId::parse(input.trim()) else {
+                        info_popup(app, Type::Error, "Error", "This is not a valid Matrix ID.")
+                            .await?;
+                        continue;
+                    };
+                    id
+                }
+            };
+
+            app.set_screen(
+                Screen::Login,
+                format!("Looking up the homeserver of {}…", id.server_name()),
+            )?;
+
+            // This uses the `.well-known/matrix/client` file of the server.
+            let builder = builder.server_name(id.server_name());
+            user_id = Some(id);
+            builder
+        } else {
+            let homeserver = match given_homeserver.take() {
+                Some(homeserver) => homeserver,
+                None => {
+                    input_popup(
+                        app,
+                        "Homeserver URL",
+                        "Please Input your homeserver URL here.",
+                    )
+                    .await?
+                }
+            };
+
+            app.set_screen(Screen::Login, "Checking homeserver…")?;
+
+            builder.homeserver_url(homeserver.trim())
+        };
+
+        match builder.build().await {
+            Ok(probe) => {
+                let issuer = discover_issuer(&probe).await;
+                let client_session = ClientSession {
+                    homeserver: probe.homeserver().to_string(),
+                    db_path,
+                    passphrase,
+                };
+
+                // We use the SQLite store, which is enabled by default. This is the crucial part to
+                // persist the encryption setup.
+                // Note that other store backends are available and you can even implement your own.
+                let client = client_session
+                    .client_builder(issuer.is_none())
+                    .build()
+                    .await?;
+
+                return Ok((client, client_session, user_id, issuer, lock));
             }
             Err(error) => match &error {
                 matrix_sdk::ClientBuildError::AutoDiscovery(_)
                 | matrix_sdk::ClientBuildError::Url(_)
                 | matrix_sdk::ClientBuildError::Http(_) => {
-                    println!("Error checking the homeserver: {error}");
-                    println!("Please try again\n");
+                    let body = if discover {
+                        format!("{error}\nThe homeserver could not be discovered, please input its URL instead.")
+                    } else {
+                        format!("{error}\nPlease try again.")
+                    };
+                    // Only try the discovery once, then fall back to the URL.
+                    discover = false;
+
+                    info_popup(app, Type::Error, "Error checking the homeserver", &body).await?;
                 }
                 _ => {
                     // Forward other errors, it's unlikely we can retry with a different outcome.
@@ -132,16 +421,73 @@ pub async fn build_client(data_dir: &Path) -> anyhow::Result<(Client, ClientSess
     }
 }
 
-/// Persist the sync token for a future session.
-/// Note that this is needed only when using `sync_once`. Other sync methods get
-/// the sync token from the store.
-pub async fn persist_sync_token(session_file: &Path, sync_token: String) -> anyhow::Result<()> {
-    let serialized_session = fs::read_to_string(session_file).await?;
-    let mut full_session: FullSession = serde_json::from_str(&serialized_session)?;
+/// Remove the session file and the store of a session.
+///
+/// The store can't be reused with another session, so it goes with it.
+pub async fn remove_session(session_file: &SessionFile) -> anyhow::Result<()> {
+    let full_session = session_file.read().await?;
+
+    let db_path = &full_session.client_session.db_path;
+    if db_path.exists() {
+        fs::remove_dir_all(db_path).await?;
+    }
+    let sync_state = sync_state::path(session_file.path());
+    if sync_state.exists() {
+        fs::remove_file(sync_state).await?;
+    }
+    fs::remove_file(session_file.path()).await?;
+
+    Ok(())
+}
+
+/// Log out on the homeserver, then remove the session and its store.
+pub async fn logout(client: &Client, session_file: &SessionFile) -> anyhow::Result<()> {
+    logout_on_homeserver(client).await?;
+    remove_session(session_file).await
+}
+
+/// Log out on the homeserver, keeping the session and its store.
+pub async fn logout_on_homeserver(client: &Client) -> anyhow::Result<()> {
+    match client.matrix_auth().logout().await {
+        Ok(_) => {}
+        // The homeserver already forgot about this session.
+        Err(error)
+            if matches!(
+                error.client_api_error_kind(),
+                Some(ErrorKind::UnknownToken { .. })
+            ) => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    Ok(())
+}
+
+/// Persist new tokens for the session, after they were refreshed or after
+/// logging in again.
+pub async fn persist_session_tokens(
+    session_file: &SessionFile,
+    tokens: MatrixSessionTokens,
+) -> anyhow::Result<()> {
+    let mut full_session = session_file.read().await?;
+
+    full_session.user_session.tokens = tokens;
+    session_file.write(&full_session).await?;
+
+    Ok(())
+}
+
+/// Persist new tokens for the session after logging in again with an OpenID
+/// Connect provider, with the session they were obtained with.
+pub async fn persist_oidc_session(
+    session_file: &SessionFile,
+    tokens: MatrixSessionTokens,
+    oidc: OidcSession,
+) -> anyhow::Result<()> {
+    let mut full_session = session_file.read().await?;
 
-    full_session.sync_token = Some(sync_token);
-    let serialized_session = serde_json::to_string(&full_session)?;
-    fs::write(session_file, serialized_session).await?;
+    full_session.user_session.tokens = tokens;
+    full_session.oidc = Some(oidc);
+    session_file.write(&full_session).await?;
 
     Ok(())
 }
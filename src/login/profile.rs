@@ -0,0 +1,201 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+use tokio::fs;
+
+use crate::ui_elements::{
+    app::App,
+    info_popup::{info_popup, Type},
+    input_popup::input_popup,
+    select_popup::select_popup,
+};
+
+/// The profile used when none was created yet.
+const DEFAULT_PROFILE: &str = "default";
+
+/// A named profile, with its own session file and store.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// The name of the profile.
+    pub name: String,
+
+    /// The folder containing the session file and the store.
+    dir: PathBuf,
+}
+
+impl Profile {
+    fn new(data_dir: &Path, name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            dir: profiles_dir(data_dir).join(name),
+        }
+    }
+
+    /// The folder containing the session file and the store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The file where the session is persisted.
+    pub fn session_file(&self) -> PathBuf {
+        self.dir.join("session")
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.session_file().exists() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} (not logged in)", self.name)
+        }
+    }
+}
+
+enum ProfileChoice {
+    Existing(Profile),
+    New,
+}
+
+impl fmt::Display for ProfileChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileChoice::Existing(profile) => write!(f, "{profile}"),
+            ProfileChoice::New => write!(f, "New profile…"),
+        }
+    }
+}
+
+/// The folder containing all the profiles.
+pub(super) fn profiles_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("profiles")
+}
+
+/// List the existing profiles, sorted by name.
+pub(super) async fn list_profiles(data_dir: &Path) -> anyhow::Result<Vec<Profile>> {
+    let mut profiles = Vec::new();
+
+    let dir = profiles_dir(data_dir);
+    if !dir.exists() {
+        return Ok(profiles);
+    }
+
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            profiles.push(Profile::new(data_dir, &name));
+        }
+    }
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(profiles)
+}
+
+/// Move the session from before profiles existed to the default profile.
+///
+/// The store stays where it is, its path is in the session.
+async fn migrate_legacy_session(data_dir: &Path) -> anyhow::Result<()> {
+    let legacy_session_file = data_dir.join("session");
+    if !legacy_session_file.exists() {
+        return Ok(());
+    }
+
+    let profile = Profile::new(data_dir, DEFAULT_PROFILE);
+    if profile.session_file().exists() {
+        return Ok(());
+    }
+
+    fs::create_dir_all(profile.dir()).await?;
+    fs::rename(legacy_session_file, profile.session_file()).await?;
+
+    Ok(())
+}
+
+/// Whether the name can be used as a folder name on every platform.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Let the user pick a profile, or create a new one.
+///
+/// The profiles whose session file is in `open` are not offered, they are
+/// already used. If `name` is set, that profile is used without asking, and
+/// created if needed.
+pub async fn pick_profile(
+    app: &mut App,
+    data_dir: &Path,
+    open: &[PathBuf],
+    name: Option<&str>,
+) -> anyhow::Result<Profile> {
+    migrate_legacy_session(data_dir).await?;
+
+    if let Some(name) = name {
+        if !is_valid_name(name) {
+            bail!("'{name}' is not a valid profile name, only letters, digits, '-' and '_' are allowed.");
+        }
+
+        let profile = Profile::new(data_dir, name);
+        if open.contains(&profile.session_file()) {
+            bail!("The profile '{name}' is already logged in.");
+        }
+
+        fs::create_dir_all(profile.dir()).await?;
+        return Ok(profile);
+    }
+
+    let profiles = list_profiles(data_dir).await?;
+    if profiles.is_empty() {
+        let profile = Profile::new(data_dir, DEFAULT_PROFILE);
+        fs::create_dir_all(profile.dir()).await?;
+        return Ok(profile);
+    }
+
+    let mut choices: Vec<_> = profiles
+        .into_iter()
+        .filter(|profile| !open.contains(&profile.session_file()))
+        .map(ProfileChoice::Existing)
+        .collect();
+    choices.push(ProfileChoice::New);
+
+    let choice = select_popup(
+        app,
+        "Choose a profile:",
+        "Each profile has its own account and data.",
+        choices,
+    )
+    .await?;
+
+    match choice {
+        ProfileChoice::Existing(profile) => Ok(profile),
+        ProfileChoice::New => loop {
+            let name = input_popup(
+                app,
+                "Profile name:",
+                "Only letters, digits, '-' and '_' are allowed.",
+            )
+            .await?;
+            let name = name.trim();
+
+            if !is_valid_name(name) {
+                info_popup(app, Type::Error, "Error", "This is not a valid name.").await?;
+                continue;
+            }
+
+            let profile = Profile::new(data_dir, name);
+            if profile.dir().exists() {
+                info_popup(app, Type::Error, "Error", "This profile already exists.").await?;
+                continue;
+            }
+
+            fs::create_dir_all(profile.dir()).await?;
+            break Ok(profile);
+        },
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail};
+use matrix_sdk::{
+    ruma::{
+        api::client::{
+            account::register::v3::Request as RegistrationRequest,
+            error::ErrorKind,
+            uiaa::{AuthData, AuthType, Dummy, RegistrationToken, UiaaInfo},
+        },
+        UserId,
+    },
+    Client,
+};
+use serde::Deserialize;
+use zeroize::Zeroize;
+
+use crate::ui_elements::{
+    app::{App, Screen},
+    info_popup::{info_popup, Type},
+    input_popup::{input_popup, prefilled_input_popup, secret_input_popup},
+    select_popup::select_popup,
+};
+
+/// The stages of the user-interactive authentication we know how to complete.
+const SUPPORTED_STAGES: &[&str] = &[
+    "m.login.dummy",
+    "m.login.terms",
+    "m.login.registration_token",
+];
+
+/// The parameters of the `m.login.terms` stage.
+#[derive(Debug, Deserialize)]
+struct TermsParams {
+    #[serde(rename = "m.login.terms")]
+    terms: Option<Terms>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Terms {
+    policies: BTreeMap<String, Policy>,
+}
+
+/// A policy the user must accept, with its translations keyed by language.
+#[derive(Debug, Deserialize)]
+struct Policy {
+    version: String,
+
+    #[serde(flatten)]
+    translations: BTreeMap<String, PolicyTranslation>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PolicyTranslation {
+    name: String,
+    url: String,
+}
+
+/// Asks the user for what the registration stages need.
+pub(super) trait RegistrationPrompt {
+    /// The registration token given by the homeserver admins.
+    async fn registration_token(&mut self) -> anyhow::Result<String>;
+
+    /// Whether the user accepts the given policies, one per line.
+    async fn accept_terms(&mut self, policies: &[String]) -> anyhow::Result<bool>;
+}
+
+impl RegistrationPrompt for App {
+    async fn registration_token(&mut self) -> anyhow::Result<String> {
+        let token = input_popup(
+            self,
+            "Registration token:",
+            "This homeserver requires a token to create an account.",
+        )
+        .await?;
+
+        Ok(token.trim().to_owned())
+    }
+
+    async fn accept_terms(&mut self, policies: &[String]) -> anyhow::Result<bool> {
+        let mut body = vec!["Please read and accept the following policies:".to_owned()];
+        body.extend_from_slice(policies);
+
+        let choice = select_popup(
+            self,
+            "Terms and conditions",
+            body.join("\n").as_str(),
+            vec!["I accept", "I decline"],
+        )
+        .await?;
+
+        Ok(choice == "I accept")
+    }
+}
+
+/// Create a new account and log in with it.
+///
+/// The username is pre-filled with the localpart of `user_id` if it is known.
+///
+/// Returns `false` if the homeserver doesn't allow registration, after telling
+/// the user.
+pub async fn register(
+    app: &mut App,
+    client: &Client,
+    user_id: Option<&UserId>,
+) -> anyhow::Result<bool> {
+    let body = "Creating a new account…";
+    let mut username = user_id
+        .map(|user_id| user_id.localpart().to_owned())
+        .unwrap_or_default();
+    let device_name = app.config().device_display_name.clone();
+
+    let password = loop {
+        username = prefilled_input_popup(app, "Username:", body, &username)
+            .await?
+            .trim()
+            .to_owned();
+
+        let password = secret_input_popup(app, "Password:", body).await?;
+        let confirmation = secret_input_popup(app, "Confirm the password:", body).await?;
+        if *password != *confirmation {
+            info_popup(app, Type::Error, "Error", "The passwords don't match.").await?;
+            continue;
+        }
+
+        app.set_screen(Screen::Login, "Creating the account…")?;
+        let Some(error) =
+            send_registration(client, app, &username, &password, &device_name).await?
+        else {
+            break password;
+        };
+
+        let message = match error.client_api_error_kind() {
+            Some(ErrorKind::Forbidden { .. }) => {
+                info_popup(
+                    app,
+                    Type::Error,
+                    "Error",
+                    "Registration is disabled on this homeserver.",
+                )
+                .await?;
+                return Ok(false);
+            }
+            Some(ErrorKind::UserInUse) => "This username is already taken.".to_owned(),
+            Some(ErrorKind::InvalidUsername) => "This username is not valid.".to_owned(),
+            Some(ErrorKind::WeakPassword) => "This password is too weak.".to_owned(),
+            _ => match error
+                .as_uiaa_response()
+                .and_then(|info| info.auth_error.as_ref())
+            {
+                Some(auth_error) => auth_error.message.clone(),
+                None => error.to_string(),
+            },
+        };
+        info_popup(app, Type::Error, "Error", &message).await?;
+    };
+
+    // The homeserver may not log us in after registering.
+    if !client.logged_in() {
+        client
+            .matrix_auth()
+            .login_username(&username, &password)
+            .initial_device_display_name(&device_name)
+            .request_refresh_token()
+            .await?;
+    }
+
+    info_popup(
+        app,
+        Type::Informaton,
+        "Account created!",
+        format!("Logged in as {username}").as_str(),
+    )
+    .await?;
+
+    Ok(true)
+}
+
+/// A registration request whose password is zeroed when it is dropped.
+struct WipedRequest(RegistrationRequest);
+
+impl Drop for WipedRequest {
+    fn drop(&mut self) {
+        if let Some(password) = &mut self.0.password {
+            password.zeroize();
+        }
+    }
+}
+
+/// Send the registration request, completing the stages the homeserver asks
+/// for.
+///
+/// Returns the error of the homeserver if the account couldn't be created.
+async fn send_registration(
+    client: &Client,
+    prompt: &mut impl RegistrationPrompt,
+    username: &str,
+    password: &str,
+    device_name: &str,
+) -> anyhow::Result<Option<matrix_sdk::Error>> {
+    let mut request = WipedRequest(RegistrationRequest::new());
+    request.0.username = Some(username.to_owned());
+    request.0.password = Some(password.to_owned());
+    request.0.initial_device_display_name = Some(device_name.to_owned());
+    request.0.refresh_token = true;
+
+    loop {
+        // The SDK takes the request by value, this copy is out of our reach.
+        let error = match client.matrix_auth().register(request.0.clone()).await {
+            Ok(_) => return Ok(None),
+            Err(error) => error,
+        };
+
+        match error.as_uiaa_response() {
+            // The homeserver wants us to complete another stage.
+            Some(info) if info.auth_error.is_none() => {
+                request.0.auth = Some(next_stage(prompt, info).await?);
+            }
+            _ => return Ok(Some(error)),
+        }
+    }
+}
+
+/// Complete the next stage of the user-interactive authentication.
+async fn next_stage(
+    prompt: &mut impl RegistrationPrompt,
+    info: &UiaaInfo,
+) -> anyhow::Result<AuthData> {
+    let session = info.session.clone();
+
+    // Find a flow we can complete entirely.
+    let flow = info
+        .flows
+        .iter()
+        .find(|flow| {
+            flow.stages
+                .iter()
+                .all(|stage| SUPPORTED_STAGES.contains(&stage.as_ref()))
+        })
+        .ok_or_else(|| {
+            anyhow!("This homeserver requires a registration step this client doesn't support.")
+        })?;
+
+    let stage = flow
+        .stages
+        .iter()
+        .find(|stage| !info.completed.contains(stage))
+        .ok_or_else(|| anyhow!("The homeserver asked for a stage that was already completed."))?;
+
+    match stage {
+        AuthType::Dummy => {
+            let mut dummy = Dummy::new();
+            dummy.session = session;
+            Ok(AuthData::Dummy(dummy))
+        }
+        AuthType::RegistrationToken => {
+            let token = prompt.registration_token().await?;
+
+            let mut registration_token = RegistrationToken::new(token);
+            registration_token.session = session;
+            Ok(AuthData::RegistrationToken(registration_token))
+        }
+        _ if stage.as_ref() == "m.login.terms" => {
+            accept_terms(prompt, info).await?;
+            Ok(AuthData::new("m.login.terms", session, Default::default())?)
+        }
+        _ => bail!("Unsupported registration step: {}", stage.as_ref()),
+    }
+}
+
+/// Show the policies of the homeserver and ask the user to accept them.
+async fn accept_terms(prompt: &mut impl RegistrationPrompt, info: &UiaaInfo) -> anyhow::Result<()> {
+    let params: TermsParams = serde_json::from_str(info.params.get())?;
+
+    let mut policies = Vec::new();
+    for policy in params
+        .terms
+        .iter()
+        .flat_map(|terms| terms.policies.values())
+    {
+        let translation = policy
+            .translations
+            .get("en")
+            .or_else(|| policy.translations.values().next());
+        if let Some(PolicyTranslation { name, url }) = translation {
+            policies.push(format!("{name} (version {}): {url}", policy.version));
+        }
+    }
+
+    if !prompt.accept_terms(&policies).await? {
+        bail!("The policies must be accepted to create an account.");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::api::client::error::ErrorKind;
+    use serde_json::{json, Value};
+    use wiremock::{
+        matchers::{body_partial_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{send_registration, RegistrationPrompt};
+    use crate::test_utils::{client, error_response, mock_homeserver};
+
+    /// Answers the prompts without asking anyone.
+    struct Answers {
+        token: String,
+        accept: bool,
+        policies: Vec<String>,
+    }
+
+    impl Default for Answers {
+        fn default() -> Self {
+            Self {
+                token: "token".to_owned(),
+                accept: true,
+                policies: Vec::new(),
+            }
+        }
+    }
+
+    impl RegistrationPrompt for Answers {
+        async fn registration_token(&mut self) -> anyhow::Result<String> {
+            Ok(self.token.clone())
+        }
+
+        async fn accept_terms(&mut self, policies: &[String]) -> anyhow::Result<bool> {
+            self.policies = policies.to_vec();
+            Ok(self.accept)
+        }
+    }
+
+    /// A homeserver asking for the given flows, and creating the account once
+    /// the request has an `auth` matching `auth`.
+    async fn homeserver_with_flows(flows: Value, params: Value, auth: Value) -> MockServer {
+        let server = mock_homeserver().await;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/register"))
+            .and(body_partial_json(json!({ "auth": auth })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": "@alice:example.org",
+            })))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/register"))
+            .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+                "flows": flows,
+                "params": params,
+                "session": "session",
+            })))
+            .mount(&server)
+            .await;
+
+        server
+    }
+
+    async fn register(
+        server: &MockServer,
+        answers: &mut Answers,
+    ) -> anyhow::Result<Option<matrix_sdk::Error>> {
+        let client = client(server).await;
+        send_registration(&client, answers, "alice", "password", "test").await
+    }
+
+    #[tokio::test]
+    async fn dummy_stage() {
+        let server = homeserver_with_flows(
+            json!([{ "stages": ["m.login.dummy"] }]),
+            json!({}),
+            json!({ "type": "m.login.dummy", "session": "session" }),
+        )
+        .await;
+
+        let error = register(&server, &mut Answers::default()).await.unwrap();
+        assert!(error.is_none(), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn terms_stage() {
+        let server = homeserver_with_flows(
+            json!([{ "stages": ["m.login.terms"] }]),
+            json!({
+                "m.login.terms": {
+                    "policies": {
+                        "privacy_policy": {
+                            "version": "1.0",
+                            "en": {
+                                "name": "Privacy Policy",
+                                "url": "https://example.org/privacy",
+                            },
+                        },
+                    },
+                },
+            }),
+            json!({ "type": "m.login.terms", "session": "session" }),
+        )
+        .await;
+
+        let mut answers = Answers::default();
+        let error = register(&server, &mut answers).await.unwrap();
+        assert!(error.is_none(), "{error:?}");
+        assert_eq!(
+            answers.policies,
+            ["Privacy Policy (version 1.0): https://example.org/privacy"]
+        );
+    }
+
+    #[tokio::test]
+    async fn declined_terms() {
+        let server = homeserver_with_flows(
+            json!([{ "stages": ["m.login.terms"] }]),
+            json!({ "m.login.terms": { "policies": {} } }),
+            json!({ "type": "m.login.terms" }),
+        )
+        .await;
+
+        let mut answers = Answers {
+            accept: false,
+            ..Default::default()
+        };
+        assert!(register(&server, &mut answers).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn registration_token_stage() {
+        let server = homeserver_with_flows(
+            json!([{ "stages": ["m.login.registration_token"] }]),
+            json!({}),
+            json!({
+                "type": "m.login.registration_token",
+                "token": "secret",
+                "session": "session",
+            }),
+        )
+        .await;
+
+        let mut answers = Answers {
+            token: "secret".to_owned(),
+            ..Default::default()
+        };
+        let error = register(&server, &mut answers).await.unwrap();
+        assert!(error.is_none(), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn unsupported_flow() {
+        let server = homeserver_with_flows(
+            json!([{ "stages": ["m.login.recaptcha", "m.login.dummy"] }]),
+            json!({}),
+            json!({ "type": "m.login.dummy" }),
+        )
+        .await;
+
+        let error = register(&server, &mut Answers::default())
+            .await
+            .expect_err("The flow can't be completed");
+        assert!(error.to_string().contains("doesn't support"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn registration_disabled() {
+        let server = mock_homeserver().await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/register"))
+            .respond_with(error_response(403, "M_FORBIDDEN"))
+            .mount(&server)
+            .await;
+
+        let error = register(&server, &mut Answers::default())
+            .await
+            .unwrap()
+            .expect("The registration should fail");
+        assert!(
+            matches!(
+                error.client_api_error_kind(),
+                Some(ErrorKind::Forbidden { .. })
+            ),
+            "{error:?}"
+        );
+    }
+}
@@ -0,0 +1,479 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, bail};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{aead::Aead, Key, KeyInit, XChaCha20Poly1305, XNonce};
+use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::{fs, io::AsyncWriteExt};
+use zeroize::Zeroizing;
+
+use super::{error::SessionError, persist_session::FullSession};
+use crate::ui_elements::{
+    app::App,
+    info_popup::{info_popup, Type},
+    input_popup::secret_input_popup,
+};
+
+/// The version of the format of the session file written by this client.
+const VERSION: u64 = 1;
+
+/// The migrations of the format of the session, the one at index `n` upgrades
+/// a session from version `n` to version `n + 1`.
+///
+/// A migration is needed each time `FullSession` changes in a way older files
+/// can't be deserialized anymore.
+const MIGRATIONS: [fn(&mut Map<String, Value>); VERSION as usize] = [
+    // Version 0 is the format from before it was versioned, only the version
+    // is missing.
+    |_| {},
+];
+
+/// The length of the salt used to derive the key from the passphrase.
+const SALT_LEN: usize = 16;
+
+/// The length of the nonce of XChaCha20-Poly1305.
+const NONCE_LEN: usize = 24;
+
+/// A session encrypted with a key derived from a passphrase, as written on
+/// disk.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedSession {
+    /// The version of the format, missing in files from before it was
+    /// versioned.
+    #[serde(default)]
+    version: u64,
+
+    /// The salt of the key derivation, in base64.
+    salt: String,
+
+    /// The nonce of the encryption, in base64.
+    nonce: String,
+
+    /// The serialized `FullSession`, encrypted and in base64.
+    ciphertext: String,
+}
+
+/// A key derived from the passphrase protecting a session file.
+struct SessionKey {
+    salt: [u8; SALT_LEN],
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl SessionKey {
+    /// Derive a key from the passphrase with Argon2.
+    fn derive(passphrase: &str, salt: [u8; SALT_LEN]) -> anyhow::Result<Self> {
+        let mut key = Zeroizing::new([0; 32]);
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, key.as_mut())
+            .map_err(|err| anyhow!("Could not derive the key from the passphrase: {err}"))?;
+
+        Ok(Self { salt, key })
+    }
+
+    /// Derive a key from the passphrase with a new salt.
+    fn generate(passphrase: &str) -> anyhow::Result<Self> {
+        let mut salt = [0; SALT_LEN];
+        thread_rng().fill_bytes(&mut salt);
+        Self::derive(passphrase, salt)
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(self.key.as_ref()))
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<EncryptedSession> {
+        let mut nonce = [0; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher()
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .map_err(|_| anyhow!("Could not encrypt the session."))?;
+
+        Ok(EncryptedSession {
+            version: VERSION,
+            salt: STANDARD.encode(self.salt),
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        })
+    }
+
+    fn decrypt(&self, encrypted: &EncryptedSession) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        let nonce = STANDARD.decode(&encrypted.nonce).map_err(corrupt)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(SessionError::Corrupt("invalid nonce".to_owned()).into());
+        }
+        let ciphertext = STANDARD.decode(&encrypted.ciphertext).map_err(corrupt)?;
+
+        let plaintext = self
+            .cipher()
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| anyhow!("Wrong passphrase."))?;
+
+        Ok(Zeroizing::new(plaintext))
+    }
+}
+
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionKey").finish_non_exhaustive()
+    }
+}
+
+/// The file where a session is persisted.
+///
+/// It can be protected by a passphrase, in which case it must be unlocked
+/// before being read or written. The clones share the key, so they all follow
+/// a change of passphrase.
+#[derive(Debug, Clone)]
+pub struct SessionFile {
+    path: PathBuf,
+
+    /// The key encrypting the file, if it is protected by a passphrase.
+    key: Arc<RwLock<Option<Arc<SessionKey>>>>,
+}
+
+impl SessionFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            key: Default::default(),
+        }
+    }
+
+    /// The location of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Whether the file is protected by a passphrase.
+    pub fn is_protected(&self) -> bool {
+        self.key().is_some()
+    }
+
+    fn key(&self) -> Option<Arc<SessionKey>> {
+        self.key.read().unwrap().clone()
+    }
+
+    /// Read the file, and return the encrypted session if it is encrypted or
+    /// the session otherwise.
+    async fn read_file(&self) -> anyhow::Result<Result<EncryptedSession, Value>> {
+        let serialized = fs::read(&self.path).await?;
+        let value: Value = serde_json::from_slice(&serialized).map_err(corrupt)?;
+
+        if value.get("ciphertext").is_none() {
+            return Ok(Err(value));
+        }
+
+        let encrypted: EncryptedSession = serde_json::from_value(value).map_err(corrupt)?;
+        if encrypted.version > VERSION {
+            return Err(SessionError::TooNew(encrypted.version).into());
+        }
+
+        Ok(Ok(encrypted))
+    }
+
+    /// Read the encrypted session, if the file is encrypted.
+    async fn read_encrypted(&self) -> anyhow::Result<Option<EncryptedSession>> {
+        Ok(self.read_file().await?.ok())
+    }
+
+    /// Whether the file on disk is encrypted and must be unlocked first.
+    pub async fn is_locked(&self) -> anyhow::Result<bool> {
+        Ok(self.key().is_none() && self.read_encrypted().await?.is_some())
+    }
+
+    /// Unlock the file with its passphrase.
+    ///
+    /// Fails if the passphrase is wrong.
+    pub async fn unlock(&self, passphrase: &str) -> anyhow::Result<()> {
+        let Some(encrypted) = self.read_encrypted().await? else {
+            return Ok(());
+        };
+
+        let salt = STANDARD
+            .decode(&encrypted.salt)
+            .map_err(corrupt)?
+            .try_into()
+            .map_err(|_| SessionError::Corrupt("invalid salt".to_owned()))?;
+        let key = SessionKey::derive(passphrase, salt)?;
+
+        // Check the passphrase right away.
+        key.decrypt(&encrypted)?;
+        *self.key.write().unwrap() = Some(Arc::new(key));
+
+        Ok(())
+    }
+
+    /// Read the session, migrated to the current version of the format.
+    pub async fn read(&self) -> anyhow::Result<FullSession> {
+        let value = match (self.read_file().await?, self.key()) {
+            (Ok(encrypted), Some(key)) => {
+                serde_json::from_slice(&key.decrypt(&encrypted)?).map_err(corrupt)?
+            }
+            (Ok(_), None) => bail!("The session file is locked."),
+            (Err(value), _) => value,
+        };
+
+        Ok(serde_json::from_value(migrate(value)?).map_err(corrupt)?)
+    }
+
+    /// Write the session, encrypted if the file is protected.
+    ///
+    /// The file is replaced at once, so it is never left half-written.
+    pub async fn write(&self, session: &FullSession) -> anyhow::Result<()> {
+        let mut value = serde_json::to_value(session)?;
+        value["version"] = VERSION.into();
+        let serialized = Zeroizing::new(serde_json::to_string(&value)?);
+
+        let serialized = match self.key() {
+            Some(key) => {
+                Zeroizing::new(serde_json::to_string(&key.encrypt(serialized.as_bytes())?)?)
+            }
+            None => serialized,
+        };
+        write_atomic(&self.path, serialized.as_bytes()).await
+    }
+
+    /// Use a new passphrase for the next writes, or none with `None`.
+    pub fn set_key(&self, passphrase: Option<&str>) -> anyhow::Result<()> {
+        *self.key.write().unwrap() = passphrase
+            .map(SessionKey::generate)
+            .transpose()?
+            .map(Arc::new);
+
+        Ok(())
+    }
+
+    /// Protect the file with a new passphrase, or remove the protection with
+    /// `None`.
+    ///
+    /// The file must be unlocked first.
+    pub async fn set_passphrase(&self, passphrase: Option<&str>) -> anyhow::Result<()> {
+        let session = self.read().await?;
+
+        self.set_key(passphrase)?;
+        self.write(&session).await
+    }
+}
+
+fn corrupt(error: impl ToString) -> SessionError {
+    SessionError::Corrupt(error.to_string())
+}
+
+/// Upgrade a serialized session to the current version of the format.
+fn migrate(value: Value) -> Result<Value, SessionError> {
+    let Value::Object(mut session) = value else {
+        return Err(SessionError::Corrupt("not an object".to_owned()));
+    };
+
+    let version = match session.remove("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| SessionError::Corrupt("invalid version".to_owned()))?,
+        None => 0,
+    };
+    if version > VERSION {
+        return Err(SessionError::TooNew(version));
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut session);
+    }
+
+    Ok(Value::Object(session))
+}
+
+/// Write the file through a temporary file which is then renamed over it,
+/// so a crash can't leave it half-written.
+///
+/// Only the owner can read the file, as it contains secrets.
+pub(super) async fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let suffix: String = thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(6)
+        .map(char::from)
+        .collect();
+    let tmp_path = path.with_extension(format!("tmp-{suffix}"));
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let result = async {
+        let mut file = options.open(&tmp_path).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, path).await
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+
+    Ok(result?)
+}
+
+/// Ask for a new passphrase twice, until both match.
+///
+/// An empty passphrase means no protection.
+pub async fn new_passphrase(app: &mut App) -> anyhow::Result<Option<Zeroizing<String>>> {
+    loop {
+        let passphrase = secret_input_popup(
+            app,
+            "New master passphrase:",
+            "Leave it empty to store the session unencrypted.",
+        )
+        .await?;
+        if passphrase.is_empty() {
+            return Ok(None);
+        }
+
+        let confirmation =
+            secret_input_popup(app, "Confirm the passphrase:", "Type it again.").await?;
+        if *passphrase == *confirmation {
+            return Ok(Some(passphrase));
+        }
+
+        info_popup(app, Type::Error, "Error", "The passphrases don't match.").await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::json;
+    use tokio::fs;
+
+    use super::{SessionFile, VERSION};
+    use crate::{
+        login::{
+            error::SessionError,
+            persist_session::{FullSession, LoginMethod},
+        },
+        test_utils::{full_session, temp_path},
+    };
+
+    fn session() -> FullSession {
+        FullSession {
+            login_method: LoginMethod::Sso { idp: None },
+            ..full_session(Path::new("/nonexistent"))
+        }
+    }
+
+    /// Read a session file with the given contents.
+    async fn read(contents: &str) -> anyhow::Result<FullSession> {
+        let session_file = SessionFile::new(temp_path("session"));
+        fs::write(session_file.path(), contents).await.unwrap();
+
+        let session = session_file.read().await;
+        let _ = fs::remove_file(session_file.path()).await;
+        session
+    }
+
+    #[tokio::test]
+    async fn encrypted_round_trip() {
+        let session_file = SessionFile::new(temp_path("session"));
+        session_file.set_key(Some("passphrase")).unwrap();
+        session_file.write(&session()).await.unwrap();
+        let contents = fs::read_to_string(session_file.path()).await.unwrap();
+
+        // Another instance has to unlock the file first.
+        let other = SessionFile::new(session_file.path().to_owned());
+        let locked = other.is_locked().await.unwrap();
+        let read_locked = other.read().await;
+        let wrong_passphrase = other.unlock("wrong").await;
+        let unlocked = other.unlock("passphrase").await;
+        let read = other.read().await;
+        let _ = fs::remove_file(session_file.path()).await;
+
+        assert!(!contents.contains("access_token"), "{contents}");
+        assert!(locked);
+        assert!(read_locked.is_err());
+        assert!(wrong_passphrase.is_err());
+        unlocked.unwrap();
+        let read = read.unwrap();
+        assert_eq!(read.user_session.tokens.access_token, "access_token");
+        assert_eq!(read.login_method, LoginMethod::Sso { idp: None });
+    }
+
+    #[tokio::test]
+    async fn migrates_unversioned_files() {
+        // Files from before the version only lack it, and the login method.
+        let mut value = serde_json::to_value(session()).unwrap();
+        let fields = value.as_object_mut().unwrap();
+        fields.remove("version");
+        fields.remove("login_method");
+
+        let session = read(&value.to_string()).await.unwrap();
+
+        assert_eq!(session.user_session.meta.device_id, "DEVICEID");
+        assert_eq!(session.login_method, LoginMethod::Password);
+    }
+
+    #[tokio::test]
+    async fn rejects_newer_files() {
+        let mut value = serde_json::to_value(session()).unwrap();
+        value["version"] = (VERSION + 1).into();
+        let plain = read(&value.to_string()).await.unwrap_err();
+
+        let encrypted = json!({
+            "version": VERSION + 1,
+            "salt": "",
+            "nonce": "",
+            "ciphertext": "",
+        });
+        let encrypted = read(&encrypted.to_string()).await.unwrap_err();
+
+        for error in [plain, encrypted] {
+            assert!(
+                matches!(
+                    error.downcast_ref::<SessionError>(),
+                    Some(SessionError::TooNew(version)) if *version == VERSION + 1
+                ),
+                "{error:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn detects_corrupt_files() {
+        for contents in ["not json", "[]", r#"{"version": "one"}"#, "{}"] {
+            let error = read(contents).await.unwrap_err();
+            assert!(
+                matches!(
+                    error.downcast_ref::<SessionError>(),
+                    Some(SessionError::Corrupt(_))
+                ),
+                "{contents}: {error:?}"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn only_the_owner_can_read_it() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let session_file = SessionFile::new(temp_path("session"));
+        session_file.write(&session()).await.unwrap();
+        let metadata = fs::metadata(session_file.path()).await;
+        let _ = fs::remove_file(session_file.path()).await;
+
+        assert_eq!(metadata.unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
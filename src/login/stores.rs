@@ -0,0 +1,343 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use super::{
+    lock::is_locked,
+    profile::{list_profiles, profiles_dir},
+    session_file::{write_atomic, SessionFile},
+};
+
+/// Which store belongs to which session, as written on disk.
+///
+/// It is needed for the sessions protected by a passphrase, whose store can't
+/// be read from the session file without unlocking it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Registry {
+    /// The store of each session file.
+    stores: BTreeMap<PathBuf, PathBuf>,
+}
+
+/// A store found in the data folder.
+#[derive(Debug)]
+pub struct Store {
+    /// The folder of the store.
+    pub path: PathBuf,
+
+    /// The size of the store, in bytes.
+    pub size: u64,
+
+    /// The session file of the session using the store, if any.
+    pub owner: Option<PathBuf>,
+}
+
+impl Store {
+    /// Whether no session uses this store anymore.
+    pub fn is_orphan(&self) -> bool {
+        self.owner.is_none()
+    }
+}
+
+impl fmt::Display for Store {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({})",
+            self.path.to_string_lossy(),
+            format_size(self.size)
+        )
+    }
+}
+
+/// The files the SDK creates in a SQLite store, one is enough to recognize
+/// it.
+const STORE_FILES: &[&str] = &["matrix-sdk-state.sqlite3", "matrix-sdk-crypto.sqlite3"];
+
+/// Whether the folder is a store of the SDK.
+fn is_store(path: &Path) -> bool {
+    STORE_FILES.iter().any(|file| path.join(file).exists())
+}
+
+fn registry_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("stores.json")
+}
+
+/// Load the registry, a missing or unreadable one is rebuilt as the sessions
+/// are restored.
+async fn load_registry(data_dir: &Path) -> Registry {
+    match fs::read(registry_path(data_dir)).await {
+        Ok(serialized) => serde_json::from_slice(&serialized).unwrap_or_default(),
+        Err(_) => Registry::default(),
+    }
+}
+
+/// Remember which store the session in `session_file` uses.
+///
+/// The file must be unlocked.
+pub async fn register_store(data_dir: &Path, session_file: &SessionFile) -> anyhow::Result<()> {
+    let session = session_file.read().await?;
+
+    let mut registry = load_registry(data_dir).await;
+    // Forget the sessions that were removed.
+    registry
+        .stores
+        .retain(|session_file, _| session_file.exists());
+    registry.stores.insert(
+        session_file.path().to_owned(),
+        session.client_session.db_path().to_owned(),
+    );
+
+    write_atomic(
+        &registry_path(data_dir),
+        serde_json::to_string(&registry)?.as_bytes(),
+    )
+    .await
+}
+
+/// List the stores in the data folder, with the session each one belongs to.
+///
+/// `open` contains the session files of the accounts logged in, they are
+/// unlocked. If a session can't be matched with its store, the stores without
+/// a session are left out as it may be one of them.
+///
+/// The profiles used by other instances are skipped, a login in progress may
+/// be using a store that no session file mentions yet.
+pub async fn list_stores(data_dir: &Path, open: &[SessionFile]) -> anyhow::Result<Vec<Store>> {
+    let registry = load_registry(data_dir).await;
+
+    let mut owners = HashMap::new();
+    let mut unresolved = false;
+    for profile in list_profiles(data_dir).await? {
+        let path = profile.session_file();
+        if !path.exists() {
+            continue;
+        }
+
+        let session_file = open
+            .iter()
+            .find(|session_file| session_file.path() == path)
+            .cloned()
+            .unwrap_or_else(|| SessionFile::new(path.clone()));
+
+        let db_path = if session_file.is_locked().await.unwrap_or(true) {
+            registry.stores.get(&path).cloned()
+        } else {
+            session_file
+                .read()
+                .await
+                .ok()
+                .map(|session| session.client_session.db_path().to_owned())
+        };
+
+        match db_path {
+            Some(db_path) => {
+                owners.insert(db_path, path);
+            }
+            None => unresolved = true,
+        }
+    }
+
+    // The stores are in the profiles, or directly in the data folder for the
+    // sessions from before profiles existed.
+    let mut parents = vec![data_dir.to_owned()];
+    parents.extend(
+        list_profiles(data_dir)
+            .await?
+            .into_iter()
+            .filter(|profile| {
+                let session_file = profile.session_file();
+                open.iter().any(|open| open.path() == session_file) || !is_locked(profile.dir())
+            })
+            .map(|profile| profile.dir().to_owned()),
+    );
+
+    let mut stores = Vec::new();
+    for parent in parents {
+        let mut entries = fs::read_dir(&parent).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if !entry.file_type().await?.is_dir()
+                || path == profiles_dir(data_dir)
+                || !is_store(&path)
+            {
+                continue;
+            }
+
+            let owner = owners.get(&path).cloned();
+            if owner.is_none() && unresolved {
+                continue;
+            }
+
+            stores.push(Store {
+                size: dir_size(&path).await,
+                path,
+                owner,
+            });
+        }
+    }
+
+    Ok(stores)
+}
+
+/// Delete a store no session uses anymore.
+pub async fn delete_store(store: &Store) -> anyhow::Result<()> {
+    if !store.is_orphan() {
+        bail!("This store is used by a session.");
+    }
+
+    fs::remove_dir_all(&store.path).await?;
+
+    Ok(())
+}
+
+/// The size of the files in the folder, counting the subfolders.
+async fn dir_size(path: &Path) -> u64 {
+    let mut size = 0;
+
+    let mut dirs = vec![path.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(mut entries) = fs::read_dir(&dir).await else {
+            continue;
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                size += metadata.len();
+            }
+        }
+    }
+
+    size
+}
+
+/// Format a size in bytes for humans.
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{size} {}", UNITS[unit])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        path::{Path, PathBuf},
+    };
+
+    use fs2::FileExt;
+    use tokio::fs;
+
+    use super::{list_stores, register_store, STORE_FILES};
+    use crate::{
+        login::{profile::profiles_dir, session_file::SessionFile},
+        test_utils::{full_session, temp_path},
+    };
+
+    /// Create a store of the SDK in the given folder.
+    async fn create_store(path: &Path) {
+        fs::create_dir_all(path).await.unwrap();
+        fs::write(path.join(STORE_FILES[0]), b"").await.unwrap();
+    }
+
+    /// Create a profile with a session protected by a passphrase, using the
+    /// store `store` of the profile.
+    async fn create_profile(data_dir: &Path, name: &str, store: &str) -> (PathBuf, SessionFile) {
+        let dir = profiles_dir(data_dir).join(name);
+        create_store(&dir.join(store)).await;
+
+        let session_file = SessionFile::new(dir.join("session"));
+        session_file.set_key(Some("passphrase")).unwrap();
+        session_file
+            .write(&full_session(&dir.join(store)))
+            .await
+            .unwrap();
+
+        (dir, session_file)
+    }
+
+    /// The stores that are listed, with whether they are orphans.
+    async fn stores(data_dir: &Path) -> Vec<(PathBuf, bool)> {
+        let mut stores: Vec<_> = list_stores(data_dir, &[])
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|store| (store.path.clone(), store.is_orphan()))
+            .collect();
+        stores.sort();
+        stores
+    }
+
+    #[tokio::test]
+    async fn registered_stores_are_kept() {
+        let data_dir = temp_path("data");
+        let (dir, session_file) = create_profile(&data_dir, "alice", "store").await;
+        register_store(&data_dir, &session_file).await.unwrap();
+        create_store(&dir.join("old_store")).await;
+
+        // The session is locked, only the registry tells its store.
+        let stores = stores(&data_dir).await;
+        let _ = fs::remove_dir_all(&data_dir).await;
+
+        assert_eq!(
+            stores,
+            [(dir.join("old_store"), true), (dir.join("store"), false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn stores_of_other_instances_are_skipped() {
+        let data_dir = temp_path("data");
+        let (dir, session_file) = create_profile(&data_dir, "alice", "store").await;
+        register_store(&data_dir, &session_file).await.unwrap();
+        // A login in progress in another instance, without a session file
+        // yet.
+        let other_dir = profiles_dir(&data_dir).join("bob");
+        create_store(&other_dir.join("store")).await;
+        let lock = File::create(other_dir.join("lock")).unwrap();
+        lock.try_lock_exclusive().unwrap();
+
+        let stores = stores(&data_dir).await;
+        drop(lock);
+        let _ = fs::remove_dir_all(&data_dir).await;
+
+        assert_eq!(stores, [(dir.join("store"), false)]);
+    }
+
+    #[tokio::test]
+    async fn stores_are_kept_while_a_session_is_unresolved() {
+        let data_dir = temp_path("data");
+        let (dir, session_file) = create_profile(&data_dir, "alice", "store").await;
+        register_store(&data_dir, &session_file).await.unwrap();
+        // Locked and not registered, this session may use any store.
+        create_profile(&data_dir, "bob", "store").await;
+        create_store(&data_dir.join("legacy_store")).await;
+
+        let stores = stores(&data_dir).await;
+        let _ = fs::remove_dir_all(&data_dir).await;
+
+        assert_eq!(stores, [(dir.join("store"), false)]);
+    }
+}
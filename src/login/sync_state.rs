@@ -0,0 +1,153 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    sync::watch,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use super::session_file::{write_atomic, SessionFile};
+
+/// How long the sync tokens are coalesced before the latest one is written.
+const DEBOUNCE: Duration = Duration::from_secs(10);
+
+/// The state of the sync, as written on disk.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PersistedSyncState {
+    /// The latest sync token.
+    sync_token: Option<String>,
+}
+
+/// The state of the sync of a session.
+///
+/// It changes with every sync response, so it is kept apart from the session
+/// file and written in the background at most once every few seconds. Only
+/// `flush()` makes sure the pending token is written: the background writer
+/// tries when the state is dropped, but it doesn't get to run if the runtime
+/// is shutting down.
+///
+/// The sync token is only needed when using `Client::sync_once()`, to not
+/// receive all the initial sync again.
+///
+/// A read-only state is only kept in memory, the instance using the profile
+/// writes it.
+#[derive(Debug)]
+pub struct SyncState {
+    path: PathBuf,
+
+    /// The latest sync token.
+    sync_token: watch::Sender<Option<String>>,
+
+    /// The task writing the sync token, unless the state is read-only.
+    writer: Option<JoinHandle<()>>,
+}
+
+impl SyncState {
+    /// Load the sync state of the session persisted in `session_file`.
+    ///
+    /// A missing or unreadable state only means starting the sync over.
+    pub async fn load(session_file: &SessionFile, read_only: bool) -> Self {
+        let path = path(session_file.path());
+
+        let persisted = match fs::read(&path).await {
+            Ok(serialized) => serde_json::from_slice(&serialized).unwrap_or_default(),
+            Err(_) => PersistedSyncState::default(),
+        };
+
+        let (sender, receiver) = watch::channel(persisted.sync_token);
+        let writer = (!read_only).then(|| tokio::spawn(write_sync_tokens(path.clone(), receiver)));
+
+        Self {
+            path,
+            sync_token: sender,
+            writer,
+        }
+    }
+
+    /// The latest sync token.
+    pub fn sync_token(&self) -> Option<String> {
+        self.sync_token.borrow().clone()
+    }
+
+    /// Set the latest sync token, it is written a bit later.
+    pub fn set_sync_token(&self, sync_token: String) {
+        self.sync_token.send_replace(Some(sync_token));
+    }
+
+    /// Write the latest sync token now.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        write(&self.path, self.sync_token()).await
+    }
+
+    /// Stop writing the sync state, before the session is removed.
+    pub fn discard(&self) {
+        if let Some(writer) = &self.writer {
+            writer.abort();
+        }
+    }
+}
+
+/// The file where the sync state of the session persisted in `session_file`
+/// is written.
+pub(super) fn path(session_file: &Path) -> PathBuf {
+    session_file.with_file_name("sync_state")
+}
+
+async fn write(path: &Path, sync_token: Option<String>) -> anyhow::Result<()> {
+    let serialized = serde_json::to_vec(&PersistedSyncState { sync_token })?;
+    write_atomic(path, &serialized).await
+}
+
+/// Write the sync tokens as they change, coalescing the ones received within
+/// `DEBOUNCE`.
+///
+/// This stops once the state is dropped, after writing the pending token if
+/// the runtime lets it run.
+async fn write_sync_tokens(path: PathBuf, mut sync_token: watch::Receiver<Option<String>>) {
+    let mut closed = false;
+
+    while !closed && sync_token.changed().await.is_ok() {
+        let deadline = Instant::now() + DEBOUNCE;
+        loop {
+            match time::timeout_at(deadline, sync_token.changed()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => {
+                    closed = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+
+        // If this fails, the next start syncs from an older token, which is
+        // only slower.
+        let latest = sync_token.borrow_and_update().clone();
+        let _ = write(&path, latest).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{path, SyncState};
+    use crate::{login::session_file::SessionFile, test_utils::temp_path};
+
+    #[tokio::test]
+    async fn read_only_state_is_not_written() {
+        let session_file = SessionFile::new(temp_path("profile").join("session"));
+        let sync_state = SyncState::load(&session_file, true).await;
+
+        sync_state.set_sync_token("token".to_owned());
+        sync_state.flush().await.unwrap();
+
+        assert_eq!(sync_state.sync_token().as_deref(), Some("token"));
+        assert!(!path(session_file.path()).exists());
+    }
+}
@@ -1,9 +1,27 @@
+mod account;
+mod cli;
+mod config;
+mod events;
 pub mod login;
 mod sync;
+#[cfg(test)]
+mod test_utils;
 pub mod ui_elements;
 
-use self::login::login;
-use self::sync::sync;
+use std::{fs::OpenOptions, path::Path, sync::Mutex};
+
+use anyhow::bail;
+use clap::Parser;
+
+use self::account::Account;
+use self::cli::{Cli, Command};
+use self::config::Config;
+use self::login::{login, persist_session::logout, restore};
+use self::sync::Cancelled;
+use self::ui_elements::{
+    app::{App, Screen},
+    info_popup::{info_popup, Type},
+};
 
 /// A simple program that adapts to the different login methods offered by a
 /// Matrix homeserver.
@@ -12,10 +30,102 @@ use self::sync::sync;
 /// or both.
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+
+    // The terminal is used by the app, so the logs can only go to a file.
+    if let Some(log_file) = &cli.log_file {
+        init_logs(log_file)?;
+    }
+
+    // An invalid configuration is reported once the app can show it.
+    let (config, config_error) = match Config::load() {
+        Ok(config) => (config, None),
+        Err(error) => (Config::default(), Some(error)),
+    };
+
+    let options = cli.login_options(&config);
+    let mut app = App::new(options.data_dir.clone(), config)?;
+
+    if let Some(error) = config_error {
+        let message = format!("{error:#}\n\nThe default configuration is used instead.");
+        info_popup(&mut app, Type::Error, "Configuration error", &message).await?;
+    }
+
+    match cli.command.unwrap_or_default() {
+        Command::Run => {
+            let account = match Account::login(&mut app, &options, &[]).await {
+                Ok(account) => account,
+                // Quitting during the initial sync is not an error.
+                Err(error) if error.is::<Cancelled>() => return Ok(()),
+                Err(error) => return Err(error),
+            };
+
+            // The app runs until the user quits or every account stopped syncing.
+            app.run(account).await
+        }
+        Command::Login => {
+            let (client, _, session_file, _lock) = login(&mut app, &options, &[]).await?;
+            drop(app);
+
+            println!(
+                "Logged in as {}, the session is in '{}'.",
+                client
+                    .user_id()
+                    .expect("A logged-in client should have a user ID"),
+                session_file.path().to_string_lossy()
+            );
+            Ok(())
+        }
+        Command::Logout => {
+            let (client, session_file, lock) = restore(&mut app, &options).await?;
+            if lock.is_read_only() {
+                bail!("This profile is used by another instance, log out from it instead.");
+            }
+
+            let user_id = client
+                .user_id()
+                .expect("A logged-in client should have a user ID")
+                .to_owned();
+            // Like from the room list, the room keys are offered to be
+            // exported first.
+            if !app.confirm_logout(user_id.as_str(), &client).await? {
+                return Ok(());
+            }
+            app.set_screen(Screen::Login, format!("Logging out of {user_id}…"))?;
+            logout(&client, &session_file).await?;
+            drop(app);
+
+            println!("Logged out of {user_id}.");
+            Ok(())
+        }
+        Command::Whoami => {
+            let (client, session_file, _lock) = restore(&mut app, &options).await?;
+            drop(app);
+
+            println!(
+                "{}",
+                client
+                    .user_id()
+                    .expect("A logged-in client should have a user ID")
+            );
+            if let Some(device_id) = client.device_id() {
+                println!("Device: {device_id}");
+            }
+            println!("Homeserver: {}", client.homeserver());
+            println!("Session: {}", session_file.path().to_string_lossy());
+            Ok(())
+        }
+    }
+}
+
+/// Write the logs to the given file, appending to it.
+fn init_logs(path: &Path) -> anyhow::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    tracing_subscriber::fmt()
+        .with_writer(Mutex::new(file))
+        .with_ansi(false)
+        .init();
 
-    let (client, sync_token, session_file) = login().await?;
-    sync(client, sync_token, &session_file)
-        .await
-        .map_err(Into::into)
+    Ok(())
 }
@@ -1,72 +1,869 @@
-use crate::login::persist_session::persist_sync_token;
-use crate::ui_elements::chat::chat;
+use crate::events::{AccountEvent, EventSender};
+use crate::login::sync_state::SyncState;
+use crate::ui_elements::app::{App, Screen};
+use anyhow::bail;
+use crossterm::event::KeyCode;
+use futures_util::{pin_mut, StreamExt};
 use matrix_sdk::{
-    config::SyncSettings, ruma::api::client::filter::FilterDefinition, Client, Error, LoopCtrl,
+    config::SyncSettings,
+    reqwest::Url,
+    ruma::{
+        api::client::{error::ErrorKind, sync::sync_events::v4::RoomSubscription},
+        events::StateEventType,
+        OwnedRoomId,
+    },
+    sliding_sync::{SlidingSync, SlidingSyncList, SlidingSyncMode},
+    sync::SyncResponse,
+    Client, HttpError, LoopCtrl,
 };
-use std::path::Path;
+use rand::{thread_rng, Rng};
+use std::{
+    collections::HashSet,
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{sleep, sleep_until, timeout},
+};
+
+/// The delay before the first retry.
+const MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// The maximum delay between two retries.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// The state of the connection to the homeserver.
+#[derive(Debug, Clone)]
+pub enum Connection {
+    /// A sync request is in flight and didn't succeed yet.
+    Connecting,
+
+    /// The last sync request succeeded.
+    Online,
+
+    /// The last sync request failed.
+    Offline {
+        /// The error of the last request.
+        error: String,
+
+        /// When the next attempt happens, or `None` if the sync stopped.
+        retry_at: Option<Instant>,
+    },
+}
+
+/// Exponential backoff, with jitter so clients don't retry all at once after
+/// an outage.
+#[derive(Debug, Default)]
+struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    /// The delay before the next attempt.
+    fn next_delay(&mut self) -> Duration {
+        let max = MIN_DELAY
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(MAX_DELAY);
+        self.attempts = self.attempts.saturating_add(1);
+
+        // Wait between half and all of the maximum delay.
+        max.mul_f64(thread_rng().gen_range(0.5..=1.0))
+    }
+
+    fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// The delay before retrying after the error: the one the homeserver asked
+/// for if it rate-limited us, or the next one of the backoff.
+fn retry_delay(error: &matrix_sdk::Error, backoff: &mut Backoff) -> Duration {
+    match error.client_api_error_kind() {
+        Some(ErrorKind::LimitExceeded {
+            retry_after_ms: Some(delay),
+        }) => *delay,
+        _ => backoff.next_delay(),
+    }
+}
+
+/// Whether the error is likely to go away by itself, like a connection error
+/// or an overloaded server.
+///
+/// The other errors, like an invalid access token, need the user to act, so
+/// it's no use retrying.
+fn is_transient(error: &matrix_sdk::Error) -> bool {
+    let matrix_sdk::Error::Http(error) = error else {
+        return false;
+    };
+
+    match error {
+        HttpError::Reqwest(_) => true,
+        _ => error.as_client_api_error().is_some_and(|error| {
+            error.status_code.is_server_error() || error.status_code.as_u16() == 429
+        }),
+    }
+}
+
+/// Sends the state of the connection of an account to the app.
+struct ConnectionReporter {
+    events: EventSender,
+
+    /// Whether the last request succeeded.
+    online: AtomicBool,
+}
+
+impl ConnectionReporter {
+    fn new(events: EventSender) -> Self {
+        Self {
+            events,
+            online: AtomicBool::new(false),
+        }
+    }
+
+    fn send(&self, connection: Connection) {
+        self.online
+            .store(matches!(connection, Connection::Online), Ordering::Relaxed);
+        self.events.send(AccountEvent::Connection(connection));
+    }
+
+    /// A request succeeded, the rooms may have changed.
+    fn synced(&self) {
+        if !self.online.load(Ordering::Relaxed) {
+            self.send(Connection::Online);
+        }
+        self.events.send(AccountEvent::RoomsUpdated);
+    }
+
+    /// The sync stopped because of the given error.
+    fn stopped(&self, error: &matrix_sdk::Error) {
+        // The session watcher reports the logout too, but maybe after the app
+        // sees that the sync stopped.
+        if let Some(ErrorKind::UnknownToken { soft_logout }) = error.client_api_error_kind() {
+            self.events.send(AccountEvent::LoggedOut {
+                soft_logout: *soft_logout,
+            });
+        }
+        self.send(Connection::Offline {
+            error: error.to_string(),
+            retry_at: None,
+        });
+        self.events.send(AccountEvent::SyncStopped);
+    }
+}
+
+/// Wait until the given time, redrawing each second for the countdown of the
+/// status bar.
+async fn wait_until(retry_at: Instant, events: &EventSender) {
+    loop {
+        events.send(AccountEvent::Tick);
+
+        let remaining = retry_at.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return;
+        }
+        sleep(remaining.min(Duration::from_secs(1))).await;
+    }
+}
+
+/// The user cancelled the initial sync.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The initial sync was cancelled.")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// What happened so far during the initial sync, shown while waiting for it.
+///
+/// The rooms and events are only known once the response arrived, so they
+/// are only shown in the summary, which stays until a key is pressed.
+struct InitialSyncProgress {
+    started: Instant,
+
+    /// The number of failed attempts.
+    retries: u32,
+
+    /// The error of the last attempt and when the next one happens, while
+    /// waiting to retry.
+    last_error: Option<(String, Instant)>,
+
+    /// The number of rooms in the response.
+    rooms: usize,
+
+    /// The number of events in the response, if they were counted.
+    events: Option<usize>,
+}
+
+impl InitialSyncProgress {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            retries: 0,
+            last_error: None,
+            rooms: 0,
+            events: None,
+        }
+    }
+
+    /// Count the rooms and events of the response.
+    fn add(&mut self, response: &SyncResponse) {
+        let rooms = &response.rooms;
+        self.rooms += rooms.join.len() + rooms.leave.len() + rooms.invite.len();
+        let events = rooms
+            .join
+            .values()
+            .map(|room| room.timeline.events.len() + room.state.len())
+            .chain(
+                rooms
+                    .leave
+                    .values()
+                    .map(|room| room.timeline.events.len() + room.state.len()),
+            )
+            .sum::<usize>();
+        self.events = Some(self.events.unwrap_or_default() + events);
+    }
+
+    /// Show the summary of the initial sync, until a key is pressed.
+    async fn finish(&self, app: &mut App) -> anyhow::Result<()> {
+        let received = match self.events {
+            Some(events) => format!("{} rooms and {events} events", self.rooms),
+            None => format!("{} rooms", self.rooms),
+        };
+        let summary = format!(
+            "The client is ready! {received} received in {} s.\n\n\
+             Press any key to continue.",
+            self.started.elapsed().as_secs()
+        );
+
+        loop {
+            app.set_screen(Screen::Syncing, summary.as_str())?;
+            // `None` asks for a redraw, it isn't a key.
+            if app.next_key().await?.is_some() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl fmt::Display for InitialSyncProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Launching a first sync to ignore past messages…")?;
+        writeln!(f)?;
+        writeln!(f, "Elapsed: {} s", self.started.elapsed().as_secs())?;
+        writeln!(f, "Retries: {}", self.retries)?;
+
+        if let Some((error, retry_at)) = &self.last_error {
+            let seconds = retry_at.saturating_duration_since(Instant::now()).as_secs();
+            writeln!(f)?;
+            writeln!(f, "An error occurred: {error}")?;
+            writeln!(f, "Trying again in {seconds} s…")?;
+        }
+
+        writeln!(f)?;
+        write!(f, "Press Escape to cancel.")
+    }
+}
+
+/// Show the progress of the initial sync until `future` completes, updating
+/// it each second.
+///
+/// Returns `None` if the user cancelled.
+async fn show_progress<F: Future>(
+    app: &mut App,
+    progress: &InitialSyncProgress,
+    future: F,
+) -> anyhow::Result<Option<F::Output>> {
+    tokio::pin!(future);
+
+    loop {
+        app.set_screen(Screen::Syncing, progress.to_string())?;
+
+        tokio::select! {
+            output = &mut future => return Ok(Some(output)),
+            key = app.next_key() => {
+                if key?.is_some_and(|key| key.code == KeyCode::Esc) {
+                    return Ok(None);
+                }
+            }
+            () = sleep(Duration::from_secs(1)) => {}
+        }
+    }
+}
+
+/// What the user sees, which drives the subscriptions of sliding sync.
+///
+/// The rooms are given by ID, as the room list of the app isn't in the order
+/// of the sliding sync list.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Viewport {
+    /// The rooms shown in the room list, and above.
+    pub rooms: Vec<OwnedRoomId>,
+
+    /// The room open in the chat, if any.
+    pub open_room: Option<OwnedRoomId>,
+}
+
+/// The name of the list of rooms requested with sliding sync.
+const ROOM_LIST: &str = "rooms";
+
+/// How many rooms of the list are requested at once with sliding sync.
+const ROOM_BATCH: u32 = 20;
+
+/// The state events requested with the rooms with sliding sync, to show them
+/// in the room list.
+fn required_state() -> Vec<(StateEventType, String)> {
+    vec![
+        (StateEventType::RoomName, String::new()),
+        (StateEventType::RoomCanonicalAlias, String::new()),
+        (StateEventType::RoomEncryption, String::new()),
+    ]
+}
+
+/// Handle an error of the initial sync.
+///
+/// A transient error is retried after a backoff, showing the progress until
+/// then. A fatal error is returned, like a [`Cancelled`] error if the user
+/// cancelled.
+async fn retry_initial_sync(
+    app: &mut App,
+    progress: &mut InitialSyncProgress,
+    backoff: &mut Backoff,
+    connection: &ConnectionReporter,
+    error: matrix_sdk::Error,
+) -> anyhow::Result<()> {
+    if !is_transient(&error) {
+        connection.stopped(&error);
+        return Err(error.into());
+    }
+
+    let retry_at = Instant::now() + retry_delay(&error, backoff);
+    progress.retries += 1;
+    progress.last_error = Some((error.to_string(), retry_at));
+
+    if show_progress(app, progress, sleep_until(retry_at.into()))
+        .await?
+        .is_none()
+    {
+        bail!(Cancelled);
+    }
+    progress.last_error = None;
+
+    Ok(())
+}
+
+/// Handle an error of the background sync loop.
+///
+/// A transient error is retried after a backoff, or after the delay asked by
+/// the homeserver, the state of the connection showing it until then. A fatal
+/// error is returned.
+async fn retry_sync(
+    connection: &ConnectionReporter,
+    backoff: &mut Backoff,
+    error: matrix_sdk::Error,
+) -> anyhow::Result<()> {
+    if !is_transient(&error) {
+        // Let the app know the account stopped syncing.
+        connection.stopped(&error);
+        return Err(error.into());
+    }
+
+    // Start from the minimum delay again if we were online in between.
+    if connection.online.load(Ordering::Relaxed) {
+        backoff.reset();
+    }
+
+    let retry_at = Instant::now() + retry_delay(&error, backoff);
+    connection.send(Connection::Offline {
+        error: error.to_string(),
+        retry_at: Some(retry_at),
+    });
+    wait_until(retry_at, &connection.events).await;
+
+    connection.send(Connection::Connecting);
+
+    Ok(())
+}
 
 /// Setup the client to listen to new messages.
+///
+/// If the store has no rooms yet, this runs the initial sync while showing
+/// its progress. Then it spawns the sync loop in the background and returns
+/// its handle. Transient errors are retried with a backoff, the state of the
+/// connection is sent with `events`. The loop only stops on a fatal error.
+///
+/// If enabled in the configuration and supported by the homeserver, sliding
+/// sync is used, following `viewport`. Otherwise the regular sync is used.
+///
+/// Returns a [`Cancelled`] error if the user cancelled the initial sync.
 pub async fn sync(
+    app: &mut App,
     client: Client,
-    initial_sync_token: Option<String>,
-    session_file: &Path,
-) -> anyhow::Result<()> {
-    println!("Launching a first sync to ignore past messages…");
+    sync_state: Arc<SyncState>,
+    events: EventSender,
+    viewport: watch::Receiver<Viewport>,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let connection = ConnectionReporter::new(events);
+    connection.send(Connection::Connecting);
 
-    // Enable room members lazy-loading, it will speed up the initial sync a lot
-    // with accounts in lots of rooms.
-    // See <https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members>.
-    let filter = FilterDefinition::with_lazy_loading();
+    // Without a proxy, the regular sync is used as the configuration says.
+    // There is no popup, as it would block starting offline.
+    if app.config().sync.sliding_sync {
+        if let Some(proxy) = sliding_sync_proxy(app, &client).await {
+            return sliding_sync(app, client, proxy, connection, viewport).await;
+        }
+    }
+
+    regular_sync(app, client, sync_state, connection).await
+}
+
+/// The sliding sync proxy to use, from the configuration or advertised by the
+/// homeserver.
+///
+/// The advertised proxy is remembered in the store, so it is known when
+/// starting offline.
+async fn sliding_sync_proxy(app: &App, client: &Client) -> Option<Url> {
+    // The URL was checked when loading the configuration.
+    if let Some(proxy) = &app.config().sync.sliding_sync_proxy {
+        return Url::parse(proxy).ok();
+    }
+
+    if let Some(proxy) = client.sliding_sync_proxy() {
+        return Some(proxy);
+    }
+
+    if let Ok(Some(proxy)) = client.store().get_custom_value(PROXY_KEY).await {
+        if let Some(proxy) = std::str::from_utf8(&proxy)
+            .ok()
+            .and_then(|proxy| Url::parse(proxy).ok())
+        {
+            return Some(proxy);
+        }
+    }
+
+    // A restored client doesn't look for the well-known file of the server,
+    // only a new one does.
+    let server_name = client.user_id()?.server_name();
+    let lookup = Client::builder().server_name(server_name).build();
+    let proxy = timeout(WELL_KNOWN_TIMEOUT, lookup)
+        .await
+        .ok()?
+        .ok()?
+        .sliding_sync_proxy()?;
+
+    // If this fails, the well-known file is only looked up again.
+    let _ = client
+        .store()
+        .set_custom_value(PROXY_KEY, proxy.as_str().as_bytes().to_vec())
+        .await;
+
+    Some(proxy)
+}
+
+/// The key of the sliding sync proxy advertised by the homeserver, in the
+/// store.
+const PROXY_KEY: &[u8] = b"persist_session.sliding_sync_proxy";
+
+/// How long to wait for the well-known file of the homeserver.
+const WELL_KNOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sync with `/sync`, the regular sync of the Matrix specification.
+async fn regular_sync(
+    app: &mut App,
+    client: Client,
+    sync_state: Arc<SyncState>,
+    connection: ConnectionReporter,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    // Room members lazy-loading is enabled by default, it speeds up the initial
+    // sync a lot with accounts in lots of rooms.
+    let filter = app.config().sync.filter();
 
     let mut sync_settings = SyncSettings::default().filter(filter.into());
 
-    // We restore the sync where we left.
-    // This is not necessary when not using `sync_once`. The other sync methods get
-    // the sync token from the store.
-    if let Some(sync_token) = initial_sync_token {
-        sync_settings = sync_settings.token(sync_token);
+    // With a sync token, the store already holds the rooms: the app starts
+    // right away from it, and the sync catches up in the background from
+    // where we left, even if the homeserver is unreachable for now.
+    if sync_state.sync_token().is_none() {
+        let mut progress = InitialSyncProgress::new();
+
+        // Let's ignore messages before the program was launched.
+        // This is a loop in case the initial sync is longer than our timeout. The
+        // server should cache the response and it will ultimately take less time to
+        // receive.
+        let mut backoff = Backoff::default();
+        loop {
+            let request = client.sync_once(sync_settings.clone());
+            let Some(result) = show_progress(app, &progress, request).await? else {
+                bail!(Cancelled);
+            };
+
+            match result {
+                Ok(response) => {
+                    progress.add(&response);
+
+                    // This is the last time we need to provide this token, the sync method after
+                    // will handle it on its own.
+                    sync_settings = sync_settings.token(response.next_batch.clone());
+                    sync_state.set_sync_token(response.next_batch);
+                    break;
+                }
+                Err(error) => {
+                    retry_initial_sync(app, &mut progress, &mut backoff, &connection, error).await?
+                }
+            }
+        }
+
+        progress.finish(app).await?;
+        connection.synced();
     }
 
-    // Let's ignore messages before the program was launched.
-    // This is a loop in case the initial sync is longer than our timeout. The
-    // server should cache the response and it will ultimately take less time to
-    // receive.
-    loop {
-        match client.sync_once(sync_settings.clone()).await {
-            Ok(response) => {
-                // This is the last time we need to provide this token, the sync method after
-                // will handle it on its own.
-                sync_settings = sync_settings.token(response.next_batch.clone());
-                persist_sync_token(session_file, response.next_batch).await?;
-                break;
+    Ok(spawn_regular_sync(
+        client,
+        sync_settings,
+        sync_state,
+        connection,
+    ))
+}
+
+/// Spawn the loop of the regular sync, continuing from the sync token.
+fn spawn_regular_sync(
+    client: Client,
+    mut sync_settings: SyncSettings,
+    sync_state: Arc<SyncState>,
+    connection: ConnectionReporter,
+) -> JoinHandle<anyhow::Result<()>> {
+    tokio::spawn(async move {
+        let sync_state = &sync_state;
+        let connection = &connection;
+        let mut backoff = Backoff::default();
+
+        // This loops until we kill the program or a fatal error happens.
+        loop {
+            // Continue from the last response, in case the loop is restarted.
+            if let Some(sync_token) = sync_state.sync_token() {
+                sync_settings = sync_settings.token(sync_token);
             }
-            Err(error) => {
-                println!("An error occurred during initial sync: {error}");
-                println!("Trying again…");
+
+            let result = client
+                .sync_with_result_callback(sync_settings.clone(), |sync_result| async move {
+                    let response = sync_result?;
+
+                    // We keep the token each time to be able to restore our session,
+                    // it is written to disk a bit later.
+                    sync_state.set_sync_token(response.next_batch);
+                    connection.synced();
+
+                    Ok(LoopCtrl::Continue)
+                })
+                .await;
+
+            let Err(error) = result else {
+                connection.events.send(AccountEvent::SyncStopped);
+                return Ok(());
+            };
+            retry_sync(connection, &mut backoff, error).await?;
+        }
+    })
+}
+
+/// Sync with sliding sync (MSC3575), through the given proxy.
+///
+/// All the rooms are requested in batches without their events, to fill the
+/// room list. The rooms the user sees are subscribed to, with their latest
+/// events.
+///
+/// The native sliding sync of homeservers (MSC4186) isn't supported by this
+/// version of the SDK, only the proxy.
+async fn sliding_sync(
+    app: &mut App,
+    client: Client,
+    proxy: Url,
+    connection: ConnectionReporter,
+    mut viewport: watch::Receiver<Viewport>,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let mut subscription = RoomSubscription::default();
+    subscription.required_state = required_state();
+    subscription.timeline_limit = Some(app.config().sync.timeline_limit.unwrap_or(1).into());
+
+    let sliding_sync = client
+        .sliding_sync("persist_session")?
+        .sliding_sync_proxy(proxy)
+        .with_all_extensions()
+        .add_list(
+            SlidingSyncList::builder(ROOM_LIST)
+                .sync_mode(SlidingSyncMode::new_growing(ROOM_BATCH))
+                .timeline_limit(0)
+                .required_state(required_state()),
+        )
+        .build()
+        .await?;
+
+    // The app starts right away from the rooms in the store, if any.
+    if client.rooms().is_empty() {
+        let mut progress = InitialSyncProgress::new();
+
+        // The first response is the initial sync.
+        let mut backoff = Backoff::default();
+        loop {
+            let stream = sliding_sync.sync();
+            pin_mut!(stream);
+
+            let Some(result) = show_progress(app, &progress, stream.next()).await? else {
+                bail!(Cancelled);
+            };
+
+            match result {
+                Some(Ok(summary)) => {
+                    // The summary doesn't say how many events came with the
+                    // rooms, only the rooms are counted.
+                    progress.rooms += summary.rooms.len();
+                    break;
+                }
+                Some(Err(error)) => {
+                    retry_initial_sync(app, &mut progress, &mut backoff, &connection, error).await?
+                }
+                None => bail!("The sliding sync stopped before the first response."),
             }
         }
+
+        progress.finish(app).await?;
+        connection.synced();
     }
 
-    println!("The client is ready! Listening to new messages…");
+    Ok(tokio::spawn(async move {
+        let mut backoff = Backoff::default();
+        let mut subscribed = HashSet::new();
+        // The rooms shown before the sync started.
+        let current = viewport.borrow_and_update().clone();
+        follow_viewport(&sliding_sync, &current, &mut subscribed, &subscription);
 
-    let client_cloned = client.clone();
-    tokio::spawn(async move {
-        let _ = chat(client_cloned).await;
-    });
+        // This loops until we kill the program or a fatal error happens.
+        loop {
+            let stream = sliding_sync.sync();
+            pin_mut!(stream);
+
+            let error = loop {
+                tokio::select! {
+                    update = stream.next() => match update {
+                        Some(Ok(_)) => connection.synced(),
+                        Some(Err(error)) => break error,
+                        None => {
+                            connection.events.send(AccountEvent::SyncStopped);
+                            return Ok(());
+                        }
+                    },
+                    // The request in flight is restarted by the changes.
+                    Ok(()) = viewport.changed() => {
+                        let viewport = viewport.borrow_and_update().clone();
+                        follow_viewport(&sliding_sync, &viewport, &mut subscribed, &subscription);
+                    }
+                }
+            };
+            retry_sync(&connection, &mut backoff, error).await?;
+        }
+    }))
+}
+
+/// Subscribe to the rooms the user sees with sliding sync, and unsubscribe
+/// from the others.
+///
+/// `subscribed` contains the rooms subscribed to so far.
+fn follow_viewport(
+    sliding_sync: &SlidingSync,
+    viewport: &Viewport,
+    subscribed: &mut HashSet<OwnedRoomId>,
+    subscription: &RoomSubscription,
+) {
+    let visible: HashSet<_> = viewport
+        .rooms
+        .iter()
+        .chain(&viewport.open_room)
+        .cloned()
+        .collect();
+
+    for room_id in subscribed.difference(&visible) {
+        sliding_sync.unsubscribe_from_room(room_id.clone());
+    }
+    for room_id in visible.difference(subscribed) {
+        sliding_sync.subscribe_to_room(room_id.clone(), Some(subscription.clone()));
+    }
+    *subscribed = visible;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use matrix_sdk::{config::SyncSettings, Client};
+    use serde_json::json;
+    use tokio::{sync::mpsc, time::timeout};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{
+        is_transient, retry_delay, spawn_regular_sync, Backoff, Connection, ConnectionReporter,
+        MAX_DELAY, MIN_DELAY,
+    };
+    use crate::{
+        events::{AccountEvent, AccountId, EventSender},
+        login::{session_file::SessionFile, sync_state::SyncState},
+        test_utils::{
+            error_response, logged_in_client, mock_homeserver, rate_limited_response, temp_path,
+            unreachable_url,
+        },
+    };
+
+    /// Mount a `/sync` answering with `response`, `times` times or forever.
+    async fn mount_sync(server: &MockServer, response: ResponseTemplate, times: Option<u64>) {
+        let mock = Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/sync"))
+            .respond_with(response);
+        match times {
+            Some(times) => mock.up_to_n_times(times).with_priority(1),
+            None => mock,
+        }
+        .mount(server)
+        .await;
+    }
 
-    // This loops until we kill the program or an error happens.
-    client
-        .sync_with_result_callback(sync_settings, |sync_result| async move {
-            let response = sync_result?;
+    /// The error of a sync with the client.
+    async fn sync_error(client: &Client) -> matrix_sdk::Error {
+        client
+            .sync_once(SyncSettings::default())
+            .await
+            .expect_err("The sync should fail")
+    }
+
+    /// The error of a sync with a homeserver answering with `response`.
+    async fn sync_error_with(response: ResponseTemplate) -> matrix_sdk::Error {
+        let server = mock_homeserver().await;
+        mount_sync(&server, response, None).await;
+
+        sync_error(&logged_in_client(&server.uri()).await).await
+    }
+
+    #[test]
+    fn backoff_stays_within_the_jitter_bounds() {
+        let mut backoff = Backoff::default();
+
+        for attempt in 0..12 {
+            let cap = MIN_DELAY.saturating_mul(1 << attempt).min(MAX_DELAY);
+            let delay = backoff.next_delay();
+            assert!(
+                cap / 2 <= delay && delay <= cap,
+                "attempt {attempt}: {delay:?} not within half of {cap:?}"
+            );
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= MIN_DELAY);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_transient() {
+        assert!(is_transient(
+            &sync_error_with(error_response(500, "M_UNKNOWN")).await
+        ));
+        assert!(is_transient(
+            &sync_error_with(error_response(503, "M_UNKNOWN")).await
+        ));
+    }
+
+    #[tokio::test]
+    async fn rate_limits_are_transient_and_say_how_long_to_wait() {
+        let error = sync_error_with(rate_limited_response(10)).await;
+        assert!(is_transient(&error));
 
-            // We persist the token each time to be able to restore our session
-            persist_sync_token(session_file, response.next_batch)
-                .await
-                .map_err(|err| Error::UnknownError(err.into()))?;
+        let mut backoff = Backoff::default();
+        assert_eq!(retry_delay(&error, &mut backoff), Duration::from_millis(10));
+        // The backoff is left for the other errors.
+        assert_eq!(backoff.attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn connection_errors_are_transient() {
+        let client = logged_in_client(&unreachable_url()).await;
+        assert!(is_transient(&sync_error(&client).await));
+    }
+
+    #[tokio::test]
+    async fn invalid_tokens_are_fatal() {
+        let error = sync_error_with(error_response(401, "M_UNKNOWN_TOKEN")).await;
+        assert!(!is_transient(&error));
+    }
+
+    #[tokio::test]
+    async fn sync_recovers_after_an_error() {
+        let server = mock_homeserver().await;
+        mount_sync(&server, error_response(502, "M_UNKNOWN"), Some(1)).await;
+        mount_sync(
+            &server,
+            ResponseTemplate::new(200).set_body_json(json!({ "next_batch": "s2" })),
+            None,
+        )
+        .await;
 
-            Ok(LoopCtrl::Continue)
+        let client = logged_in_client(&server.uri()).await;
+        // The sync state is written next to the session file.
+        let profile_dir = temp_path("sync");
+        std::fs::create_dir_all(&profile_dir).unwrap();
+        let session_file = SessionFile::new(profile_dir.join("session"));
+        let sync_state = Arc::new(SyncState::load(&session_file, false).await);
+        sync_state.set_sync_token("s1".to_owned());
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let connection = ConnectionReporter::new(EventSender::new(AccountId::unique(), sender));
+
+        let sync = spawn_regular_sync(
+            client,
+            SyncSettings::default(),
+            sync_state.clone(),
+            connection,
+        );
+
+        let mut connections = Vec::new();
+        let result = timeout(Duration::from_secs(10), async {
+            while let Some((_, event)) = receiver.recv().await {
+                if let AccountEvent::Connection(connection) = event {
+                    let online = matches!(connection, Connection::Online);
+                    connections.push(connection);
+                    if online {
+                        break;
+                    }
+                }
+            }
         })
-        .await?;
+        .await;
+        sync.abort();
+        sync_state.discard();
+        let _ = std::fs::remove_dir_all(&profile_dir);
 
-    Ok(())
+        assert!(result.is_ok(), "The sync didn't recover: {connections:?}");
+        assert!(
+            matches!(
+                connections.as_slice(),
+                [
+                    Connection::Offline {
+                        retry_at: Some(_),
+                        ..
+                    },
+                    Connection::Connecting,
+                    Connection::Online,
+                ]
+            ),
+            "{connections:?}"
+        );
+        assert_eq!(sync_state.sync_token().as_deref(), Some("s2"));
+    }
 }
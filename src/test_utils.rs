@@ -0,0 +1,146 @@
+//! Helpers for the tests, with a mock homeserver and sessions on disk.
+
+use std::{
+    net::TcpListener,
+    path::{Path, PathBuf},
+};
+
+use matrix_sdk::{
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    Client, SessionMeta,
+};
+use serde_json::json;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use crate::login::persist_session::{request_config, FullSession, LoginMethod};
+
+/// A mock homeserver, that only answers to `/versions` until more is
+/// mounted.
+pub async fn mock_homeserver() -> MockServer {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "versions": ["r0.6.1", "v1.1", "v1.2", "v1.3", "v1.4", "v1.5", "v1.6"],
+        })))
+        .mount(&server)
+        .await;
+
+    server
+}
+
+/// A client of the given homeserver, with an in-memory store.
+///
+/// It sends its requests like the clients of the app, so the tests see every
+/// error.
+pub async fn client(server: &MockServer) -> Client {
+    client_of(&server.uri()).await
+}
+
+async fn client_of(homeserver_url: &str) -> Client {
+    Client::builder()
+        .homeserver_url(homeserver_url)
+        .request_config(request_config())
+        .build()
+        .await
+        .expect("A client with an in-memory store should build")
+}
+
+/// A Matrix error response.
+pub fn error_response(status: u16, errcode: &str) -> ResponseTemplate {
+    ResponseTemplate::new(status).set_body_json(json!({
+        "errcode": errcode,
+        "error": "Mocked error",
+    }))
+}
+
+/// The URL of a homeserver that refuses connections.
+pub fn unreachable_url() -> String {
+    // Nothing listens on a port that was just released.
+    let port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("A local port should be free")
+        .port();
+
+    format!("http://127.0.0.1:{port}")
+}
+
+/// A client of a homeserver that refuses connections.
+pub async fn unreachable_client() -> Client {
+    client_of(&unreachable_url()).await
+}
+
+/// A client logged in as @alice:example.org on the given homeserver.
+pub async fn logged_in_client(homeserver_url: &str) -> Client {
+    let client = client_of(homeserver_url).await;
+
+    client
+        .restore_session(MatrixSession {
+            meta: SessionMeta {
+                user_id: "@alice:example.org".try_into().unwrap(),
+                device_id: "DEVICEID".into(),
+            },
+            tokens: MatrixSessionTokens {
+                access_token: "access_token".to_owned(),
+                refresh_token: None,
+            },
+        })
+        .await
+        .expect("The session should be restored");
+
+    client
+}
+
+/// An `M_LIMIT_EXCEEDED` response, asking to wait for the given time.
+pub fn rate_limited_response(retry_after_ms: u64) -> ResponseTemplate {
+    ResponseTemplate::new(429).set_body_json(json!({
+        "errcode": "M_LIMIT_EXCEEDED",
+        "error": "Too many requests",
+        "retry_after_ms": retry_after_ms,
+    }))
+}
+
+/// A successful `/login` response for @alice:example.org.
+pub fn login_response() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "user_id": "@alice:example.org",
+        "access_token": "access_token",
+        "refresh_token": "refresh_token",
+        "device_id": "DEVICEID",
+    }))
+}
+
+/// A session of @alice:example.org, with its store in `db_path`.
+pub fn full_session(db_path: &Path) -> FullSession {
+    FullSession {
+        client_session: serde_json::from_value(json!({
+            "homeserver": "http://localhost",
+            "db_path": db_path,
+            "passphrase": "store_passphrase",
+        }))
+        .unwrap(),
+        user_session: MatrixSession {
+            meta: SessionMeta {
+                user_id: "@alice:example.org".try_into().unwrap(),
+                device_id: "DEVICEID".into(),
+            },
+            tokens: MatrixSessionTokens {
+                access_token: "access_token".to_owned(),
+                refresh_token: None,
+            },
+        },
+        sync_token: None,
+        oidc: None,
+        login_method: LoginMethod::Password,
+    }
+}
+
+/// A path in the temporary directory, different for each call.
+pub fn temp_path(name: &str) -> PathBuf {
+    let suffix: u64 = rand::random();
+    std::env::temp_dir().join(format!("matrix-client-test-{name}-{suffix:x}"))
+}
@@ -0,0 +1,927 @@
+use std::{
+    fmt,
+    io::{self, Stdout},
+    path::PathBuf,
+};
+
+use anyhow::anyhow;
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures_util::StreamExt;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId},
+    Client, Room,
+};
+use ratatui::{prelude::*, widgets::*};
+use tui_input::{backend::crossterm::EventHandler, Input};
+
+use super::{
+    chat,
+    info_popup::{info_popup, Type},
+    input_popup::{prefilled_input_popup, secret_input_popup},
+    room_list,
+    select_popup::select_popup,
+    status_bar,
+};
+use crate::{
+    account::Account,
+    config::{Action, Config},
+    events::{AccountEvent, AccountId, EventRouter, EventSender},
+    login::{
+        session_file::new_passphrase,
+        stores::{delete_store, format_size, list_stores, Store},
+        LoginOptions,
+    },
+    sync::Viewport,
+};
+
+/// The screens the application can show.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Screen {
+    /// Logging in or restoring a previous session.
+    #[default]
+    Login,
+
+    /// Waiting for the initial sync to finish.
+    Syncing,
+
+    /// Browsing the joined rooms.
+    RoomList,
+
+    /// Chatting in the given room.
+    Chat(OwnedRoomId),
+}
+
+/// The application owning the terminal.
+///
+/// There is only one `App` for the whole lifetime of the program, every view
+/// and popup is rendered through it so the terminal never leaves the
+/// alternate screen until we quit.
+pub struct App {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+
+    /// The terminal input events.
+    events: EventStream,
+
+    /// Routes the events of the background tasks to the accounts.
+    router: EventRouter,
+
+    /// The screen currently shown.
+    screen: Screen,
+
+    /// The line shown on the login and syncing screens.
+    status: String,
+
+    /// The joined rooms, as of the last redraw.
+    rooms: Vec<Room>,
+
+    /// The selected room in the room list.
+    room_list_state: ListState,
+
+    /// The message being written in the chat.
+    input: Input,
+
+    /// The logged-in accounts.
+    accounts: Vec<Account>,
+
+    /// The index of the account currently shown.
+    current: usize,
+
+    /// The folder containing the profiles and their stores.
+    data_dir: PathBuf,
+
+    /// The configuration, which can be reloaded.
+    config: Config,
+}
+
+impl App {
+    /// Setup the terminal and create the app, with the profiles in
+    /// `data_dir`.
+    pub fn new(data_dir: PathBuf, config: Config) -> anyhow::Result<Self> {
+        enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        // We don't capture the mouse so text, like the SSO URL, can be selected.
+        execute!(stdout, EnterAlternateScreen)?;
+        let backend = CrosstermBackend::new(stdout);
+        let terminal = Terminal::new(backend)?;
+
+        Ok(Self {
+            terminal,
+            events: EventStream::new(),
+            router: EventRouter::default(),
+            screen: Screen::default(),
+            status: String::new(),
+            rooms: Vec::new(),
+            room_list_state: ListState::default(),
+            input: Input::default(),
+            accounts: Vec::new(),
+            current: 0,
+            data_dir,
+            config,
+        })
+    }
+
+    /// Switch to the given screen and redraw.
+    pub fn set_screen(&mut self, screen: Screen, status: impl Into<String>) -> anyhow::Result<()> {
+        self.screen = screen;
+        self.status = status.into();
+        self.draw(|_| {})?;
+
+        Ok(())
+    }
+
+    /// The configuration.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Where the background tasks of the given account send their events.
+    pub fn event_sender(&mut self, account: AccountId) -> EventSender {
+        self.router.sender(account)
+    }
+
+    /// Wait for the next terminal event.
+    ///
+    /// Returns `None` when an account event was applied instead, the caller
+    /// should then draw again and wait for the next event.
+    pub async fn next_event(&mut self) -> anyhow::Result<Option<Event>> {
+        tokio::select! {
+            event = self.events.next() => match event {
+                Some(event) => Ok(Some(event?)),
+                None => Err(anyhow!("The terminal event stream ended.")),
+            },
+            Some((account, event)) = self.router.recv() => {
+                self.router.route(&mut self.accounts, account, event);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Add the account at the given position, with the events received while
+    /// it wasn't there.
+    fn insert_account(&mut self, idx: usize, account: Account) {
+        self.router.insert(&mut self.accounts, idx, account);
+    }
+
+    /// Remove the current account, with its pending events.
+    fn remove_current_account(&mut self) -> Account {
+        let account = self.accounts.remove(self.current);
+        self.current = self.current.saturating_sub(1);
+        self.drop_pending_events(account.id());
+        account
+    }
+
+    /// Forget the events of an account that was removed, or never added.
+    pub fn drop_pending_events(&mut self, id: AccountId) {
+        self.router.drop_pending(&mut self.accounts, id);
+    }
+
+    /// Show the errors of the background tasks.
+    async fn show_errors(&mut self) -> anyhow::Result<()> {
+        for error in self.router.take_errors() {
+            info_popup(self, Type::Error, "Error", &error).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the next key press.
+    ///
+    /// Returns `None` when a redraw was requested instead.
+    pub async fn next_key(&mut self) -> anyhow::Result<Option<KeyEvent>> {
+        match self.next_event().await? {
+            Some(Event::Key(key)) if key.kind == KeyEventKind::Press => Ok(Some(key)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Let the current account know which rooms are shown, from the top of
+    /// the list to the bottom of the screen, and which one is open.
+    fn update_viewport(&self) -> io::Result<()> {
+        let Some(account) = self.accounts.get(self.current) else {
+            return Ok(());
+        };
+
+        let height = usize::from(self.terminal.size()?.height);
+        let selected = self.room_list_state.selected().unwrap_or_default();
+        let open_room = match &self.screen {
+            Screen::Chat(room_id) => Some(room_id.clone()),
+            _ => None,
+        };
+
+        account.set_viewport(Viewport {
+            rooms: self
+                .rooms
+                .iter()
+                .take(selected + height)
+                .map(|room| room.room_id().to_owned())
+                .collect(),
+            open_room,
+        });
+
+        Ok(())
+    }
+
+    /// Draw the current screen, then `overlay` on top of it.
+    pub fn draw(&mut self, overlay: impl FnOnce(&mut Frame)) -> io::Result<()> {
+        let Self {
+            terminal,
+            screen,
+            status,
+            rooms,
+            room_list_state,
+            input,
+            accounts,
+            current,
+            config,
+            ..
+        } = self;
+
+        terminal.draw(|f| {
+            // The screens of an account keep the last line for its status.
+            let chunks = Layout::default()
+                .constraints([Constraint::Min(0), Constraint::Length(1)])
+                .split(f.size());
+            let account = accounts.get(*current);
+
+            match screen {
+                Screen::Login | Screen::Syncing => background(f, screen, status),
+                Screen::RoomList => room_list::ui(f, chunks[0], rooms, room_list_state, config),
+                Screen::Chat(room_id) => {
+                    let room = rooms.iter().find(|room| room.room_id() == room_id);
+                    let messages = account
+                        .map(|account| account.messages(room_id))
+                        .unwrap_or_default();
+                    chat::ui(f, chunks[0], room, messages, input, &config.colors)
+                }
+            }
+            if let (Screen::RoomList | Screen::Chat(_), Some(account)) = (&*screen, account) {
+                status_bar::ui(
+                    f,
+                    chunks[1],
+                    &account.name(),
+                    account.connection(),
+                    &config.colors,
+                );
+            }
+            overlay(f);
+        })?;
+
+        Ok(())
+    }
+
+    /// Run the main loop until the user quits or no account is left.
+    ///
+    /// The state of the accounts is written before returning, even after an
+    /// error.
+    pub async fn run(&mut self, account: Account) -> anyhow::Result<()> {
+        let result = self.main_loop(account).await;
+        self.close_accounts().await?;
+
+        result
+    }
+
+    async fn main_loop(&mut self, account: Account) -> anyhow::Result<()> {
+        self.insert_account(0, account);
+        self.set_screen(Screen::RoomList, "")?;
+
+        // Failed or replaced logins leave their store behind.
+        self.review_stores(true).await?;
+
+        loop {
+            self.show_errors().await?;
+            self.remove_stopped_accounts().await?;
+            let Some(client) = self
+                .accounts
+                .get(self.current)
+                .map(|account| account.client.clone())
+            else {
+                return Ok(());
+            };
+
+            self.rooms = client.joined_rooms();
+            self.update_viewport()?;
+            self.draw(|_| {})?;
+
+            let Some(key) = self.next_key().await? else {
+                continue;
+            };
+
+            match self.screen.clone() {
+                Screen::RoomList => match self.config.keys.action(key.code) {
+                    Some(Action::Quit) => return Ok(()),
+                    Some(Action::Up) => self.select_room(-1),
+                    Some(Action::Down) => self.select_room(1),
+                    Some(Action::Accounts) => self.switch_account().await?,
+                    Some(Action::Logout) => self.logout().await?,
+                    Some(Action::Passphrase) => self.change_passphrase().await?,
+                    Some(Action::Stores) => self.review_stores(false).await?,
+                    Some(Action::ReloadConfig) => self.reload_config().await?,
+                    Some(Action::Open) => {
+                        if let Some(room) = self
+                            .room_list_state
+                            .selected()
+                            .and_then(|idx| self.rooms.get(idx))
+                        {
+                            self.screen = Screen::Chat(room.room_id().to_owned());
+                        }
+                    }
+                    None => {}
+                },
+                Screen::Chat(room_id) => match key.code {
+                    KeyCode::Esc => self.screen = Screen::RoomList,
+                    KeyCode::Enter => self.send_message(&client, &room_id).await?,
+                    _ => {
+                        self.input.handle_event(&Event::Key(key));
+                    }
+                },
+                Screen::Login | Screen::Syncing => {}
+            }
+        }
+    }
+
+    /// Read the configuration file again.
+    ///
+    /// The device name and the sync filter apply to the next logins and syncs,
+    /// the data folder to the next start.
+    async fn reload_config(&mut self) -> anyhow::Result<()> {
+        match Config::load() {
+            Ok(config) => {
+                self.config = config;
+                let message = "The configuration was reloaded.";
+                info_popup(self, Type::Informaton, "Configuration", message).await
+            }
+            Err(error) => {
+                let message = format!("{error:#}\n\nThe previous configuration is kept.");
+                info_popup(self, Type::Error, "Configuration error", &message).await
+            }
+        }
+    }
+
+    /// Stop the accounts before quitting, so their state is written.
+    async fn close_accounts(&mut self) -> anyhow::Result<()> {
+        for account in &self.accounts {
+            // Only the sync token can be lost, which is not worth failing for.
+            let _ = account.close().await;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the accounts whose sync stopped, and tell the user why.
+    ///
+    /// The accounts soft logged out by the homeserver are kept if the user
+    /// logs in again.
+    async fn remove_stopped_accounts(&mut self) -> anyhow::Result<()> {
+        // A sync task sends why it stopped before finishing.
+        self.router.route_received(&mut self.accounts);
+
+        while let Some(idx) = self
+            .accounts
+            .iter()
+            .position(|account| account.logged_out().is_some() || account.sync_finished())
+        {
+            let current = self.current;
+            let mut account = self.accounts.remove(idx);
+            let id = account.id();
+            // Its events are kept in case it logs in again.
+            self.router.expect(id);
+            if self.current > idx || self.current >= self.accounts.len() {
+                self.current = self.current.saturating_sub(1);
+            }
+            let name = account.name();
+
+            match account.logged_out() {
+                Some(true) => {
+                    if self.reauthenticate(&mut account).await? {
+                        self.insert_account(idx, account);
+                        self.current = current;
+                    }
+                }
+                Some(false) => {
+                    let body = format!(
+                        "{name} was logged out by the homeserver, its session and data are removed \
+                         from this device. Please log in again.\n\
+                         Without a backup of the room keys, the encrypted history can't be read anymore."
+                    );
+                    // The data is only removed once the user decided what to
+                    // do with the room keys.
+                    loop {
+                        let choices = vec![RemoveChoice::ExportKeys, RemoveChoice::Remove];
+                        match select_popup(self, "Logged out", &body, choices).await {
+                            Ok(RemoveChoice::ExportKeys) => {
+                                if self.export_room_keys(&account.client).await? {
+                                    break;
+                                }
+                            }
+                            Ok(RemoveChoice::Remove) => break,
+                            Err(_) => {}
+                        }
+                    }
+
+                    if let Err(error) = account.forget().await {
+                        let body = format!("Could not remove the data of {name}: {error}");
+                        info_popup(self, Type::Error, "Error", &body).await?;
+                    }
+                }
+                None => {
+                    let body = match account.sync_result().await {
+                        Ok(()) => format!("{name} stopped syncing."),
+                        Err(error) => format!("{name} stopped syncing: {error}"),
+                    };
+                    info_popup(self, Type::Error, "Sync stopped", &body).await?;
+                }
+            }
+
+            if !self.accounts.iter().any(|account| account.id() == id) {
+                self.drop_pending_events(id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ask the password to log in again after a soft logout, or go through
+    /// the SSO or the OpenID Connect provider the account logged in with.
+    ///
+    /// Returns whether the account is logged in again.
+    async fn reauthenticate(&mut self, account: &mut Account) -> anyhow::Result<bool> {
+        let body = format!(
+            "The session of {} expired. Log in again to keep this device and its encryption keys.",
+            account.name()
+        );
+
+        if account.oidc_issuer().is_some() || account.uses_sso() {
+            info_popup(self, Type::Informaton, "Session expired", &body).await?;
+
+            let result = if account.oidc_issuer().is_some() {
+                account.reauthenticate_oidc(self).await
+            } else {
+                account.reauthenticate_sso(self).await
+            };
+            return match result {
+                Ok(()) => {
+                    self.set_screen(Screen::RoomList, "")?;
+                    Ok(true)
+                }
+                Err(error) => {
+                    info_popup(self, Type::Error, "Error", error.to_string().as_str()).await?;
+                    Ok(false)
+                }
+            };
+        }
+
+        loop {
+            let Ok(password) = secret_input_popup(self, "Password:", &body).await else {
+                // The user cancelled.
+                return Ok(false);
+            };
+
+            match account.reauthenticate(self, &password).await {
+                Ok(()) => {
+                    self.set_screen(Screen::RoomList, "")?;
+                    return Ok(true);
+                }
+                Err(error) => {
+                    info_popup(self, Type::Error, "Error", error.to_string().as_str()).await?
+                }
+            }
+        }
+    }
+
+    /// Let the user switch to another account, or log in with a new one.
+    async fn switch_account(&mut self) -> anyhow::Result<()> {
+        let mut choices: Vec<_> = self
+            .accounts
+            .iter()
+            .enumerate()
+            .map(|(idx, account)| AccountChoice::Existing(idx, account.name()))
+            .collect();
+        choices.push(AccountChoice::New);
+
+        let Ok(choice) = select_popup(self, "Accounts", "Switch to the account:", choices).await
+        else {
+            // The user cancelled.
+            return Ok(());
+        };
+
+        match choice {
+            AccountChoice::Existing(idx, _) => self.current = idx,
+            AccountChoice::New => {
+                let open: Vec<_> = self
+                    .accounts
+                    .iter()
+                    .map(|account| account.session_file.path().to_owned())
+                    .collect();
+
+                let options = LoginOptions::new(self.data_dir.clone());
+                match Account::login(self, &options, &open).await {
+                    Ok(account) => {
+                        self.insert_account(self.accounts.len(), account);
+                        self.current = self.accounts.len() - 1;
+                    }
+                    Err(error) => {
+                        info_popup(self, Type::Error, "Error", error.to_string().as_str()).await?
+                    }
+                }
+            }
+        }
+
+        self.room_list_state.select(None);
+        self.set_screen(Screen::RoomList, "")?;
+
+        Ok(())
+    }
+
+    /// Log out of the current account, after offering to export its room keys.
+    async fn logout(&mut self) -> anyhow::Result<()> {
+        let name = self.accounts[self.current].name();
+        let client = self.accounts[self.current].client.clone();
+        if !self.confirm_logout(&name, &client).await? {
+            return Ok(());
+        }
+
+        self.set_screen(Screen::Login, format!("Logging out of {name}…"))?;
+        // The account is only removed once the homeserver logged it out, or
+        // if the user gives up on it.
+        if let Err(error) = self.accounts[self.current].logout().await {
+            let body = format!(
+                "Could not log out of {name}: {error}\n\n\
+                 Removing it from this device anyway leaves the session valid on the homeserver."
+            );
+            let choices = vec![LogoutFailedChoice::Keep, LogoutFailedChoice::RemoveLocally];
+            if let Ok(LogoutFailedChoice::RemoveLocally) =
+                select_popup(self, "Log out", &body, choices).await
+            {
+                let account = self.remove_current_account();
+                if let Err(error) = account.forget().await {
+                    let body = format!("Could not remove the data of {name}: {error}");
+                    info_popup(self, Type::Error, "Error", &body).await?;
+                }
+            }
+        } else {
+            self.remove_current_account();
+            info_popup(self, Type::Informaton, "Logged out", &name).await?;
+        }
+
+        self.room_list_state.select(None);
+        self.set_screen(Screen::RoomList, "")?;
+
+        Ok(())
+    }
+
+    /// Change the master passphrase protecting the session file of the
+    /// current account, or remove it.
+    async fn change_passphrase(&mut self) -> anyhow::Result<()> {
+        if self.accounts[self.current].is_read_only() {
+            let message = "This account is read-only, change it from the instance using it.";
+            return info_popup(self, Type::Error, "Error", message).await;
+        }
+
+        let Ok(passphrase) = new_passphrase(self).await else {
+            return Ok(());
+        };
+
+        let session_file = self.accounts[self.current].session_file.clone();
+        let message = match (passphrase, session_file.is_protected()) {
+            (Some(passphrase), _) => session_file
+                .set_passphrase(Some(&passphrase))
+                .await
+                .map(|()| "The session is now protected by the new passphrase."),
+            (None, true) => session_file
+                .set_passphrase(None)
+                .await
+                .map(|()| "The session is now stored unencrypted."),
+            (None, false) => return Ok(()),
+        };
+
+        match message {
+            Ok(message) => info_popup(self, Type::Informaton, "Passphrase", message).await,
+            Err(error) => {
+                info_popup(
+                    self,
+                    Type::Error,
+                    "Error",
+                    format!("Could not change the passphrase: {error}").as_str(),
+                )
+                .await
+            }
+        }
+    }
+
+    /// Offer to delete the stores no session uses anymore.
+    ///
+    /// When `quiet` is set, nothing is shown if there are none.
+    async fn review_stores(&mut self, quiet: bool) -> anyhow::Result<()> {
+        let open: Vec<_> = self
+            .accounts
+            .iter()
+            .map(|account| account.session_file.clone())
+            .collect();
+
+        let orphans: Vec<_> = match list_stores(&self.data_dir, &open).await {
+            Ok(stores) => stores.into_iter().filter(Store::is_orphan).collect(),
+            Err(error) => {
+                let message = format!("Could not list the stores: {error}");
+                return info_popup(self, Type::Error, "Error", &message).await;
+            }
+        };
+        if orphans.is_empty() {
+            if !quiet {
+                let message = "Every store belongs to a session.";
+                info_popup(self, Type::Informaton, "Stores", message).await?;
+            }
+            return Ok(());
+        }
+
+        let size = orphans.iter().map(|store| store.size).sum();
+        let body = format!(
+            "{} stores ({}) don't belong to any session, they were left by failed or replaced \
+             logins. Their encryption keys can't be used anymore.",
+            orphans.len(),
+            format_size(size)
+        );
+        let mut choices = vec![StoreChoice::DeleteAll, StoreChoice::Keep];
+        choices.extend(
+            orphans
+                .iter()
+                .enumerate()
+                .map(|(idx, store)| StoreChoice::Delete(idx, store.to_string())),
+        );
+
+        let to_delete: Vec<_> = match select_popup(self, "Orphaned stores", &body, choices).await {
+            Ok(StoreChoice::DeleteAll) => orphans.iter().collect(),
+            Ok(StoreChoice::Delete(idx, _)) => vec![&orphans[idx]],
+            Ok(StoreChoice::Keep) | Err(_) => return Ok(()),
+        };
+
+        let body = format!("Delete {} stores? This can't be undone.", to_delete.len());
+        let confirm = select_popup(
+            self,
+            "Delete the stores",
+            &body,
+            vec![ConfirmChoice::Yes, ConfirmChoice::No],
+        )
+        .await;
+        if !matches!(confirm, Ok(ConfirmChoice::Yes)) {
+            return Ok(());
+        }
+
+        for store in to_delete {
+            if let Err(error) = delete_store(store).await {
+                let message = format!("Could not delete {store}: {error}");
+                info_popup(self, Type::Error, "Error", &message).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ask whether to log out of the account of `client`, after offering to
+    /// export its room keys.
+    ///
+    /// Returns whether to go on with the logout.
+    pub async fn confirm_logout(&mut self, name: &str, client: &Client) -> anyhow::Result<bool> {
+        let body = format!(
+            "Log out of {name}? Its session and data will be removed from this device.\n\
+             Without a backup of the room keys, the encrypted history can't be read anymore."
+        );
+        let choices = vec![
+            LogoutChoice::ExportKeys,
+            LogoutChoice::LogOut,
+            LogoutChoice::Cancel,
+        ];
+
+        match select_popup(self, "Log out", &body, choices).await {
+            Ok(LogoutChoice::ExportKeys) => self.export_room_keys(client).await,
+            Ok(LogoutChoice::LogOut) => Ok(true),
+            Ok(LogoutChoice::Cancel) | Err(_) => Ok(false),
+        }
+    }
+
+    /// Export the room keys of the client of an account.
+    ///
+    /// Returns whether they were exported.
+    async fn export_room_keys(&mut self, client: &Client) -> anyhow::Result<bool> {
+        let default_path = dirs::home_dir().unwrap_or_default().join("room-keys.txt");
+
+        let Ok(path) = prefilled_input_popup(
+            self,
+            "Export the room keys to:",
+            "The file where the room keys are written.",
+            &default_path.to_string_lossy(),
+        )
+        .await
+        else {
+            return Ok(false);
+        };
+        let Ok(passphrase) = secret_input_popup(
+            self,
+            "Passphrase:",
+            "The passphrase to encrypt the room keys with. It is needed to import them.",
+        )
+        .await
+        else {
+            return Ok(false);
+        };
+
+        let path = PathBuf::from(path.trim());
+        match client
+            .encryption()
+            .export_room_keys(path, &passphrase, |_| true)
+            .await
+        {
+            Ok(()) => Ok(true),
+            Err(error) => {
+                info_popup(
+                    self,
+                    Type::Error,
+                    "Error",
+                    format!("Could not export the room keys: {error}").as_str(),
+                )
+                .await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Move the selection in the room list by `offset`, wrapping around.
+    fn select_room(&mut self, offset: isize) {
+        if self.rooms.is_empty() {
+            return;
+        }
+        let len = self.rooms.len() as isize;
+        let selected = self
+            .room_list_state
+            .selected()
+            .map_or(-1, |idx| idx as isize);
+        let idx = (selected + offset).rem_euclid(len);
+        self.room_list_state.select(Some(idx as usize));
+    }
+
+    /// Send the content of the input to the given room.
+    async fn send_message(&mut self, client: &Client, room_id: &OwnedRoomId) -> anyhow::Result<()> {
+        let body = self.input.value().trim().to_owned();
+        if body.is_empty() {
+            return Ok(());
+        }
+        // Sending may write to the encryption store.
+        if self.accounts[self.current].is_read_only() {
+            let message = "This account is read-only, send messages from the instance using it.";
+            return info_popup(self, Type::Error, "Error", message).await;
+        }
+        let Some(room) = client.get_room(room_id) else {
+            return Ok(());
+        };
+
+        // The message shows up with the next sync, a failure is reported as
+        // an event.
+        self.input.reset();
+        let events = self.accounts[self.current].events();
+        let room_id = room_id.clone();
+        tokio::spawn(async move {
+            let result = room
+                .send(RoomMessageEventContent::text_plain(&body))
+                .await
+                .map(|_| ())
+                .map_err(|error| error.to_string());
+            events.send(AccountEvent::Sent {
+                room_id,
+                body,
+                result,
+            });
+        });
+
+        Ok(())
+    }
+}
+
+impl Drop for App {
+    fn drop(&mut self) {
+        // restore terminal
+        let _ = disable_raw_mode();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+/// An item of the account switcher.
+enum AccountChoice {
+    Existing(usize, String),
+    New,
+}
+
+impl fmt::Display for AccountChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountChoice::Existing(_, name) => write!(f, "{name}"),
+            AccountChoice::New => write!(f, "Add an account…"),
+        }
+    }
+}
+
+/// An item of the logout dialog.
+#[derive(Debug)]
+enum LogoutChoice {
+    ExportKeys,
+    LogOut,
+    Cancel,
+}
+
+impl fmt::Display for LogoutChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogoutChoice::ExportKeys => write!(f, "Export the room keys, then log out"),
+            LogoutChoice::LogOut => write!(f, "Log out without exporting the room keys"),
+            LogoutChoice::Cancel => write!(f, "Cancel"),
+        }
+    }
+}
+
+/// An item of the dialog shown when the homeserver logged an account out.
+#[derive(Debug)]
+enum RemoveChoice {
+    ExportKeys,
+    Remove,
+}
+
+impl fmt::Display for RemoveChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoveChoice::ExportKeys => write!(f, "Export the room keys, then remove the data"),
+            RemoveChoice::Remove => write!(f, "Remove the data without exporting the room keys"),
+        }
+    }
+}
+
+/// An item of the dialog shown when the homeserver can't log an account out.
+#[derive(Debug)]
+enum LogoutFailedChoice {
+    Keep,
+    RemoveLocally,
+}
+
+impl fmt::Display for LogoutFailedChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogoutFailedChoice::Keep => write!(f, "Keep the account"),
+            LogoutFailedChoice::RemoveLocally => write!(f, "Remove it from this device anyway"),
+        }
+    }
+}
+
+/// An item of the orphaned stores dialog.
+enum StoreChoice {
+    DeleteAll,
+    Keep,
+    Delete(usize, String),
+}
+
+impl fmt::Display for StoreChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreChoice::DeleteAll => write!(f, "Delete them all"),
+            StoreChoice::Keep => write!(f, "Keep them"),
+            StoreChoice::Delete(_, store) => write!(f, "Delete {store}"),
+        }
+    }
+}
+
+/// An item of a confirmation dialog.
+enum ConfirmChoice {
+    Yes,
+    No,
+}
+
+impl fmt::Display for ConfirmChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfirmChoice::Yes => write!(f, "Yes"),
+            ConfirmChoice::No => write!(f, "No"),
+        }
+    }
+}
+
+/// Draw the screen shown while logging in or syncing.
+fn background(f: &mut Frame, screen: &Screen, status: &str) {
+    let title = match screen {
+        Screen::Syncing => "Syncing",
+        _ => "Login",
+    };
+
+    let block = Block::default()
+        .title(title)
+        .title_style(Style::default().bold())
+        .title_alignment(Alignment::Center)
+        .borders(Borders::ALL);
+
+    let paragraph = Paragraph::new(status)
+        .block(block)
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: true });
+
+    f.render_widget(paragraph, f.size());
+}
@@ -1,57 +1,182 @@
-//! # [Ratatui] Tabs example
-//!
-//! The latest version of this example is available in the [examples] folder in the repository.
-//!
-//! Please note that the examples are designed to be run against the `main` branch of the Github
-//! repository. This means that you may not be able to compile with the latest release version on
-//! crates.io, or the one that you have installed locally.
-//!
-//! See the [examples readme] for more information on finding examples that match the version of the
-//! library you are using.
-//!
-//! [Ratatui]: https://github.com/ratatui-org/ratatui
-//! [examples]: https://github.com/ratatui-org/ratatui/blob/main/examples
-//! [examples readme]: https://github.com/ratatui-org/ratatui/blob/main/examples/README.md
-
-#![allow(clippy::wildcard_imports, clippy::enum_glob_use)]
-
-use color_eyre::Result;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
 use matrix_sdk::{
-    ruma::events::room::message::{
-        MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
+    event_handler::Ctx,
+    ruma::{
+        events::room::message::{MessageType, OriginalSyncRoomMessageEvent},
+        OwnedRoomId, RoomId,
     },
     Client, Room, RoomState,
 };
+use ratatui::{prelude::*, widgets::*};
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+    time::sleep,
+};
+use tui_input::Input;
 
-pub async fn chat(client: Client) -> Result<()> {
-    // Now that we've synced, let's attach a handler for incoming room messages.
-    client.add_event_handler(on_room_message);
-    let joined_rooms = client.joined_rooms();
-    println!(
-        "{:?}",
-        joined_rooms
-            .iter()
-            .map(|room| room.name().unwrap_or("".to_owned()))
-            .collect::<Vec<_>>()
-    );
+use crate::{
+    config::Colors,
+    events::{AccountEvent, EventSender},
+};
 
-    let room1 = joined_rooms
-        .iter()
-        .find(|room| room.name().unwrap_or("".to_owned()) == "room1")
-        .unwrap();
-
-    let sth = room1
-        .send(RoomMessageEventContent::text_plain("hallo"))
-        .await;
-    println!("{:?}", sth);
-    Ok(())
+/// The number of messages per room kept in the store, to show them at the next
+/// start.
+const CACHED_MESSAGES: usize = 50;
+
+/// The text messages received so far, per room.
+#[derive(Debug, Default)]
+pub struct Messages(HashMap<OwnedRoomId, Vec<String>>);
+
+impl Messages {
+    /// Load the latest messages of the joined rooms, as cached in the store of
+    /// the client.
+    pub async fn load(client: &Client) -> Self {
+        let mut messages = Self::default();
+
+        for room in client.joined_rooms() {
+            let key = cache_key(room.room_id());
+            let Ok(Some(cached)) = client.store().get_custom_value(&key).await else {
+                continue;
+            };
+            if let Ok(cached) = serde_json::from_slice(&cached) {
+                messages.0.insert(room.room_id().to_owned(), cached);
+            }
+        }
+
+        messages
+    }
+
+    /// The messages received in the given room.
+    pub fn get(&self, room_id: &RoomId) -> &[String] {
+        self.0.get(room_id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Add a message to the given room, and return the latest ones to cache.
+    pub fn push(&mut self, room_id: OwnedRoomId, message: String) -> &[String] {
+        let room_messages = self.0.entry(room_id).or_default();
+        room_messages.push(message);
+
+        &room_messages[room_messages.len().saturating_sub(CACHED_MESSAGES)..]
+    }
+}
+
+/// The key of the cached messages of the room in the store.
+fn cache_key(room_id: &RoomId) -> Vec<u8> {
+    format!("persist_session.messages.{room_id}").into_bytes()
 }
 
-/// Handle room messages.
-async fn on_room_message(
+/// Keep the latest messages of the room in the store of the client.
+///
+/// If this fails, the messages are only missing at the next start.
+async fn cache_messages(client: &Client, room_id: &RoomId, messages: &[String]) {
+    if let Ok(serialized) = serde_json::to_vec(messages) {
+        let _ = client
+            .store()
+            .set_custom_value(&cache_key(room_id), serialized)
+            .await;
+    }
+}
+
+/// Keeps the latest messages of the rooms in the store, to show them at the
+/// next start.
+///
+/// A single task writes them, a bit later so the messages received together
+/// are written at once, and in order. Only `flush()` makes sure the pending
+/// messages are written.
+#[derive(Debug)]
+pub struct MessageCache {
+    client: Client,
+
+    /// The latest messages of the rooms that changed since the last write.
+    pending: Arc<std::sync::Mutex<HashMap<OwnedRoomId, Vec<String>>>>,
+
+    /// Held while writing, so the writes don't overtake each other.
+    writing: Arc<Mutex<()>>,
+
+    /// Wakes up the writer when messages are pending.
+    changed: Arc<Notify>,
+
+    /// The task writing the messages.
+    writer: JoinHandle<()>,
+}
+
+impl MessageCache {
+    pub fn new(client: Client) -> Self {
+        let pending = Arc::<std::sync::Mutex<_>>::default();
+        let writing = Arc::<Mutex<_>>::default();
+        let changed = Arc::<Notify>::default();
+
+        let writer = tokio::spawn({
+            let client = client.clone();
+            let pending = pending.clone();
+            let writing = writing.clone();
+            let changed = changed.clone();
+            async move {
+                loop {
+                    changed.notified().await;
+                    sleep(CACHE_DEBOUNCE).await;
+                    write_pending(&client, &pending, &writing).await;
+                }
+            }
+        });
+
+        Self {
+            client,
+            pending,
+            writing,
+            changed,
+            writer,
+        }
+    }
+
+    /// Cache the latest messages of the room, they are written a bit later.
+    pub fn cache(&self, room_id: OwnedRoomId, messages: Vec<String>) {
+        self.pending.lock().unwrap().insert(room_id, messages);
+        self.changed.notify_one();
+    }
+
+    /// Write the pending messages now.
+    pub async fn flush(&self) {
+        write_pending(&self.client, &self.pending, &self.writing).await;
+    }
+
+    /// Stop writing the messages, before the store is removed.
+    pub fn discard(&self) {
+        self.writer.abort();
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+impl Drop for MessageCache {
+    fn drop(&mut self) {
+        self.writer.abort();
+    }
+}
+
+/// How long the messages are coalesced before being written.
+const CACHE_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Write the pending messages, after the writes in progress.
+async fn write_pending(
+    client: &Client,
+    pending: &std::sync::Mutex<HashMap<OwnedRoomId, Vec<String>>>,
+    writing: &Mutex<()>,
+) {
+    let _writing = writing.lock().await;
+    let pending = std::mem::take(&mut *pending.lock().unwrap());
+
+    for (room_id, messages) in pending {
+        cache_messages(client, &room_id, &messages).await;
+    }
+}
+
+/// Handle room messages, by sending them to the app.
+pub async fn on_room_message(
     event: OriginalSyncRoomMessageEvent,
     room: Room,
-    // tx: Sender<OriginalSyncMessageLikeEvent<RoomMessageEventContent>>,
+    Ctx(events): Ctx<EventSender>,
 ) {
     // We only want to log text messages in joined rooms.
     if room.state() != RoomState::Joined {
@@ -61,14 +186,90 @@ async fn on_room_message(
         return;
     };
 
-    let room_name = match room.display_name().await {
-        Ok(room_name) => room_name.to_string(),
-        Err(error) => {
-            println!("Error getting room display name: {error}");
-            // Let's fallback to the room ID.
-            room.room_id().to_string()
-        }
+    // The member is in the store, so its name is known even offline.
+    let sender = match room.get_member_no_sync(&event.sender).await {
+        Ok(Some(member)) => member.name().to_owned(),
+        _ => event.sender.to_string(),
     };
 
-    println!("[{room_name}] {}: {}", event.sender, text_content.body);
+    events.send(AccountEvent::Message {
+        room_id: room.room_id().to_owned(),
+        message: format!("{sender}: {}", text_content.body),
+    });
+}
+
+/// Draw the chat of the given room.
+pub fn ui(
+    f: &mut Frame,
+    area: Rect,
+    room: Option<&Room>,
+    messages: &[String],
+    input: &Input,
+    colors: &Colors,
+) {
+    let chunks = Layout::default()
+        .constraints([Constraint::Min(0), Constraint::Length(3)])
+        .split(area);
+
+    let title = room
+        .and_then(|room| room.name())
+        .unwrap_or_else(|| "Chat".to_owned());
+
+    // Only keep the messages that fit, the latest at the bottom.
+    let height = chunks[0].height.saturating_sub(2) as usize;
+    let lines: Vec<Line> = messages
+        .iter()
+        .skip(messages.len().saturating_sub(height))
+        .map(|message| Line::from(message.as_str()))
+        .collect();
+
+    let history = Paragraph::new(lines).block(
+        Block::default()
+            .title(title)
+            .title_style(Style::default().bold())
+            .borders(Borders::ALL),
+    );
+    f.render_widget(history, chunks[0]);
+
+    let block = Block::default()
+        .title("Press Enter to send, Escape to go back.")
+        .borders(Borders::ALL)
+        .fg(colors.accent);
+    let input_widget = Paragraph::new(input.value()).block(block);
+
+    f.set_cursor(
+        chunks[1].x + input.visual_cursor() as u16 + 1,
+        chunks[1].y + 1,
+    );
+    f.render_widget(input_widget, chunks[1]);
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{owned_room_id, room_id};
+
+    use super::{Messages, CACHED_MESSAGES};
+
+    #[test]
+    fn push_returns_the_latest_messages_to_cache() {
+        let mut messages = Messages::default();
+        let room_id = owned_room_id!("!room:example.org");
+
+        assert_eq!(
+            messages.push(room_id.clone(), "first".to_owned()),
+            ["first"]
+        );
+
+        for i in 0..CACHED_MESSAGES {
+            messages.push(room_id.clone(), i.to_string());
+        }
+        let latest = messages.push(room_id.clone(), "last".to_owned());
+        assert_eq!(latest.len(), CACHED_MESSAGES);
+        assert_eq!(latest.last().map(String::as_str), Some("last"));
+        assert_eq!(latest.first().map(String::as_str), Some("1"));
+
+        // All the messages are kept to show them.
+        assert_eq!(messages.get(&room_id).len(), CACHED_MESSAGES + 2);
+        assert!(messages.get(room_id!("!other:example.org")).is_empty());
+    }
 }
@@ -1,13 +1,7 @@
-use std::io;
-
-use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyEventKind},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
-};
 use ratatui::{prelude::*, widgets::*};
 
-use super::centered_rect;
+use super::{app::App, centered_rect};
+use crate::config::Colors;
 
 #[derive(Debug)]
 pub enum Type {
@@ -15,15 +9,15 @@ pub enum Type {
     Informaton,
 }
 
-struct App<'a> {
+struct InfoPopup<'a> {
     info_type: Type,
     header: &'a str,
     body: &'a str,
 }
 
-impl App<'_> {
-    fn new<'a>(info_type: Type, header: &'a str, body: &'a str) -> App<'a> {
-        App {
+impl InfoPopup<'_> {
+    fn new<'a>(info_type: Type, header: &'a str, body: &'a str) -> InfoPopup<'a> {
+        InfoPopup {
             info_type,
             header,
             body,
@@ -31,67 +25,45 @@ impl App<'_> {
     }
 }
 
-pub fn info_popup(info_type: Type, header: &str, body: &str) -> anyhow::Result<()> {
-    // setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-
-    // create app and run it
-    let app = App::new(info_type, header, body);
-    let res = run_app(&mut terminal, app);
-
-    // restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-
-    if let Err(err) = res {
-        println!("{err:?}");
-    }
-
-    Ok(())
-}
+/// Show an information until any key is pressed.
+pub async fn info_popup(
+    app: &mut App,
+    info_type: Type,
+    header: &str,
+    body: &str,
+) -> anyhow::Result<()> {
+    let popup = InfoPopup::new(info_type, header, body);
+    let colors = app.config().colors;
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: App) -> io::Result<()> {
     loop {
-        terminal.draw(|f| ui(f, &app))?;
+        app.draw(|f| ui(f, &popup, &colors))?;
 
-        if let Event::Key(key) = event::read()? {
-            if key.kind == KeyEventKind::Press {
-                return Ok(());
-            }
+        if app.next_key().await?.is_some() {
+            return Ok(());
         }
     }
 }
 
-fn ui(f: &mut Frame, app: &App) {
+fn ui(f: &mut Frame, popup: &InfoPopup, colors: &Colors) {
     let size = f.size();
 
-    // let paragraph = Paragraph::new(app.body)
-    //     .alignment(Alignment::Center)
-    //     .wrap(Wrap { trim: true });
-
-    let fg_color = match app.info_type {
-        Type::Error => Color::Red,
-        Type::Informaton => Color::Blue,
+    let fg_color = match popup.info_type {
+        Type::Error => colors.error,
+        Type::Informaton => colors.info,
     };
     let block = Block::default()
-        .title(app.header)
+        .title(popup.header)
         .title_style(Style::default().bold())
         .title_alignment(Alignment::Center)
         .borders(Borders::ALL)
         .fg(fg_color);
 
-    let content = Paragraph::new(app.body).block(Block::default().borders(Borders::ALL));
+    let content = Paragraph::new(popup.body)
+        .wrap(Wrap { trim: true })
+        .block(Block::default().borders(Borders::ALL));
 
     let area = centered_rect(60, 15, size);
+    f.render_widget(Clear, area);
     f.render_widget(content, area);
     f.render_widget(block, area);
 }
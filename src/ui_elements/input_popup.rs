@@ -1,87 +1,165 @@
-use std::io;
-
-use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
-};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
 use ratatui::{prelude::*, widgets::*};
 use tui_input::backend::crossterm::EventHandler;
 use tui_input::Input;
+use zeroize::Zeroizing;
+
+use super::{app::App, centered_rect};
+use crate::config::Colors;
 
-use super::centered_rect;
+/// The capacity reserved for secrets, so typing one usually doesn't need to
+/// grow the buffer.
+const SECRET_CAPACITY: usize = 256;
 
-struct App<'a> {
+struct InputPopup<'a> {
     header: &'a str,
     body: &'a str,
     msg: Input,
+
+    /// The secret being typed, hidden behind bullets, if this asks for one.
+    ///
+    /// It is edited in place rather than through `msg`, which rebuilds its
+    /// buffer on most edits and would leave copies of the secret behind in
+    /// freed memory.
+    secret: Option<Zeroizing<String>>,
 }
 
-impl App<'_> {
-    fn new<'a>(header: &'a str, body: &'a str) -> App<'a> {
-        App {
+impl InputPopup<'_> {
+    fn new<'a>(header: &'a str, body: &'a str) -> InputPopup<'a> {
+        InputPopup {
             header,
             body,
             msg: Input::default(),
+            secret: None,
         }
     }
-}
 
-pub fn input_popup(header: &str, body: &str) -> anyhow::Result<String> {
-    // setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-
-    // create app and run it
-    let app = App::new(header, body);
-    let res = run_app(&mut terminal, app);
-
-    // restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-
-    if let Err(err) = res {
-        println!("{err:?}");
-        Err(err.into())
-    } else {
-        Ok(res.unwrap())
+    fn with_value<'a>(header: &'a str, body: &'a str, value: &str) -> InputPopup<'a> {
+        InputPopup {
+            header,
+            body,
+            msg: Input::new(value.to_owned()),
+            secret: None,
+        }
     }
-}
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> anyhow::Result<String> {
-    loop {
-        terminal.draw(|f| ui(f, &app))?;
-
-        if let Event::Key(key) = event::read()? {
-            if key.kind == KeyEventKind::Press {
-                match key.code {
-                    KeyCode::Enter => return Ok(app.msg.to_string()),
-                    KeyCode::Esc => return Err(anyhow::anyhow!("Exited.")),
-                    _ => {
-                        app.msg.handle_event(&Event::Key(key));
-                    }
+    fn new_secret<'a>(header: &'a str, body: &'a str) -> InputPopup<'a> {
+        InputPopup {
+            header,
+            body,
+            msg: Input::default(),
+            secret: Some(Zeroizing::new(String::with_capacity(SECRET_CAPACITY))),
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        let Some(secret) = &mut self.secret else {
+            self.msg.handle_event(&Event::Key(key));
+            return;
+        };
+
+        // Only typing at the end and erasing are supported, which never
+        // move the secret. The shortcuts with Ctrl or Alt aren't text.
+        match key.code {
+            KeyCode::Char('u') if key.modifiers == KeyModifiers::CONTROL => secret.clear(),
+            KeyCode::Char(c) if (key.modifiers - KeyModifiers::SHIFT).is_empty() => {
+                if secret.len() + c.len_utf8() > secret.capacity() {
+                    // Grow by hand, so the old buffer is zeroed when dropped.
+                    let mut grown = Zeroizing::new(String::with_capacity(secret.capacity() * 2));
+                    grown.push_str(secret);
+                    *secret = grown;
                 }
+                secret.push(c);
             }
+            KeyCode::Backspace => {
+                secret.pop();
+            }
+            _ => {}
+        }
+    }
+
+    /// The length of the input, in characters.
+    fn len(&self) -> usize {
+        match &self.secret {
+            Some(secret) => secret.chars().count(),
+            None => self.msg.value().chars().count(),
+        }
+    }
+
+    /// The position of the cursor in the input.
+    fn cursor(&self) -> usize {
+        match &self.secret {
+            Some(_) => self.len(),
+            None => self.msg.visual_cursor(),
         }
     }
 }
 
-fn ui(f: &mut Frame, app: &App) {
+/// Ask the user for a line of text.
+///
+/// The popup is redrawn whenever a background task requests it, and can be
+/// cancelled by dropping the future.
+pub async fn input_popup(app: &mut App, header: &str, body: &str) -> anyhow::Result<String> {
+    let mut popup = InputPopup::new(header, body);
+    run_popup(app, &mut popup).await?;
+
+    Ok(popup.msg.to_string())
+}
+
+/// Ask the user for a line of text, starting with the given value.
+pub async fn prefilled_input_popup(
+    app: &mut App,
+    header: &str,
+    body: &str,
+    value: &str,
+) -> anyhow::Result<String> {
+    let mut popup = InputPopup::with_value(header, body, value);
+    run_popup(app, &mut popup).await?;
+
+    Ok(popup.msg.to_string())
+}
+
+/// Ask the user for a secret, like a password.
+///
+/// The input is only shown as bullets. It is never copied while typed, and
+/// the returned buffer is zeroed when it is dropped.
+pub async fn secret_input_popup(
+    app: &mut App,
+    header: &str,
+    body: &str,
+) -> anyhow::Result<Zeroizing<String>> {
+    let mut popup = InputPopup::new_secret(header, body);
+    run_popup(app, &mut popup).await?;
+
+    // The buffer is zeroed when the popup is dropped if the user cancelled.
+    Ok(popup.secret.take().unwrap_or_default())
+}
+
+async fn run_popup(app: &mut App, popup: &mut InputPopup<'_>) -> anyhow::Result<()> {
+    let colors = app.config().colors;
+
+    loop {
+        app.draw(|f| ui(f, popup, &colors))?;
+
+        let Some(key) = app.next_key().await? else {
+            continue;
+        };
+        match key.code {
+            KeyCode::Enter => return Ok(()),
+            KeyCode::Esc => return Err(anyhow::anyhow!("Exited.")),
+            _ => popup.handle_key(key),
+        }
+    }
+}
+
+fn ui(f: &mut Frame, popup: &InputPopup, colors: &Colors) {
     let size = f.size();
 
     let chunks = Layout::default()
         .constraints([Constraint::Max(10), Constraint::Min(0)])
         .split(size);
 
-    let mut body = app.body.split('\n').map(|msg| Line::from(msg)).collect();
+    let mut body = popup.body.split('\n').map(Line::from).collect();
     let mut text = vec![Line::from(vec![Span::styled(
         "Press Enter to confirm, Escape to quit.",
         Style::default().slow_blink().bold(),
@@ -92,19 +170,72 @@ fn ui(f: &mut Frame, app: &App) {
         .alignment(Alignment::Center)
         .wrap(Wrap { trim: true });
 
+    f.render_widget(Clear, chunks[0]);
     f.render_widget(paragraph, chunks[0]);
 
     let block = Block::default()
-        .title(app.header)
+        .title(popup.header)
         .title_style(Style::default().bold())
         .borders(Borders::ALL)
-        .fg(Color::Yellow);
+        .fg(colors.accent);
 
-    let input = Paragraph::new(app.msg.value()).block(Block::default().borders(Borders::ALL));
+    let value = if popup.secret.is_some() {
+        "•".repeat(popup.len())
+    } else {
+        popup.msg.value().to_owned()
+    };
+    let input = Paragraph::new(value).block(Block::default().borders(Borders::ALL));
 
     let area = centered_rect(60, 20, size);
-    f.set_cursor(area.x + ((app.msg.visual_cursor()) as u16 + 1), area.y + 1);
+    f.set_cursor(area.x + (popup.cursor() as u16 + 1), area.y + 1);
 
+    f.render_widget(Clear, area);
     f.render_widget(input, area);
     f.render_widget(block, area);
 }
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+    use super::InputPopup;
+
+    fn type_keys(popup: &mut InputPopup, keys: &[(KeyCode, KeyModifiers)]) {
+        for &(code, modifiers) in keys {
+            popup.handle_key(KeyEvent::new(code, modifiers));
+        }
+    }
+
+    #[test]
+    fn secret_ignores_shortcuts() {
+        let mut popup = InputPopup::new_secret("Password:", "");
+        type_keys(
+            &mut popup,
+            &[
+                (KeyCode::Char('a'), KeyModifiers::NONE),
+                (KeyCode::Char('B'), KeyModifiers::SHIFT),
+                (KeyCode::Char('w'), KeyModifiers::CONTROL),
+                (KeyCode::Char('x'), KeyModifiers::ALT),
+            ],
+        );
+        assert_eq!(popup.secret.as_deref().map(String::as_str), Some("aB"));
+
+        type_keys(&mut popup, &[(KeyCode::Backspace, KeyModifiers::NONE)]);
+        assert_eq!(popup.secret.as_deref().map(String::as_str), Some("a"));
+
+        type_keys(&mut popup, &[(KeyCode::Char('u'), KeyModifiers::CONTROL)]);
+        assert_eq!(popup.secret.as_deref().map(String::as_str), Some(""));
+    }
+
+    #[test]
+    fn secret_grows_past_its_capacity() {
+        let mut popup = InputPopup::new_secret("Password:", "");
+        let long = "é".repeat(super::SECRET_CAPACITY);
+        for c in long.chars() {
+            popup.handle_key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE));
+        }
+
+        assert_eq!(popup.secret.as_deref(), Some(&long));
+        assert_eq!(popup.len(), super::SECRET_CAPACITY);
+    }
+}
@@ -1,8 +1,12 @@
 use ratatui::layout::{Constraint, Direction, Layout, Rect};
 
+pub mod app;
 pub mod chat;
 pub mod info_popup;
 pub mod input_popup;
+mod room_list;
+pub mod select_popup;
+mod status_bar;
 
 /// helper function to create a centered rect using up certain percentage of the available rect `r`
 pub fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
@@ -0,0 +1,31 @@
+use matrix_sdk::Room;
+use ratatui::{prelude::*, widgets::*};
+
+use crate::config::Config;
+
+/// Draw the list of joined rooms.
+pub fn ui(f: &mut Frame, area: Rect, rooms: &[Room], state: &mut ListState, config: &Config) {
+    let items: Vec<ListItem> = rooms
+        .iter()
+        .map(|room| ListItem::new(room.name().unwrap_or_else(|| room.room_id().to_string())))
+        .collect();
+
+    let keys = &config.keys;
+    let title = format!(
+        "Rooms (Enter to open, {} for accounts, {} for passphrase, {} for stores, \
+         {} to reload the config, {} to log out, {} to quit)",
+        keys.accounts, keys.passphrase, keys.stores, keys.reload_config, keys.logout, keys.quit
+    );
+
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title(title)
+                .title_style(Style::default().bold())
+                .borders(Borders::ALL),
+        )
+        .highlight_style(Style::default().fg(config.colors.accent).bold())
+        .highlight_symbol("> ");
+
+    f.render_stateful_widget(list, area, state);
+}
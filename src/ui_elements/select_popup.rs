@@ -0,0 +1,288 @@
+use std::fmt::Display;
+
+use crossterm::event::{Event, KeyCode, KeyModifiers};
+use ratatui::{prelude::*, widgets::*};
+use tui_input::backend::crossterm::EventHandler;
+use tui_input::Input;
+
+use super::{app::App, centered_rect};
+use crate::config::{Colors, Config};
+
+struct SelectPopup<'a> {
+    header: &'a str,
+    body: &'a str,
+
+    /// The labels of all the items.
+    labels: Vec<String>,
+
+    /// The indices of the items matching the filter.
+    visible: Vec<usize>,
+
+    /// The selected row among the visible items.
+    state: ListState,
+
+    /// The filter typed by the user.
+    filter: Input,
+}
+
+impl SelectPopup<'_> {
+    fn new<'a>(header: &'a str, body: &'a str, labels: Vec<String>) -> SelectPopup<'a> {
+        let mut popup = SelectPopup {
+            header,
+            body,
+            labels,
+            visible: Vec::new(),
+            state: ListState::default(),
+            filter: Input::default(),
+        };
+        popup.apply_filter();
+        popup
+    }
+
+    /// Update the visible items after the filter changed.
+    fn apply_filter(&mut self) {
+        let filter = self.filter.value().to_lowercase();
+        self.visible = self
+            .labels
+            .iter()
+            .enumerate()
+            .filter(|(_, label)| label.to_lowercase().contains(&filter))
+            .map(|(idx, _)| idx)
+            .collect();
+
+        let selected = if self.visible.is_empty() {
+            None
+        } else {
+            Some(0)
+        };
+        self.state.select(selected);
+    }
+
+    /// Move the selection by `offset`, wrapping around.
+    fn move_selection(&mut self, offset: isize) {
+        if self.visible.is_empty() {
+            return;
+        }
+        let len = self.visible.len() as isize;
+        let selected = self.state.selected().unwrap_or(0) as isize;
+        let idx = (selected + offset).rem_euclid(len);
+        self.state.select(Some(idx as usize));
+    }
+
+    /// The index of the selected item in the original list.
+    fn selected(&self) -> Option<usize> {
+        self.state
+            .selected()
+            .and_then(|row| self.visible.get(row))
+            .copied()
+    }
+}
+
+/// Let the user pick one of the given items and return it.
+///
+/// Navigate with the arrows, or Ctrl with the up and down keys of the
+/// configuration (`k`/`j` by default), type to filter the items and press
+/// Enter to pick one. Escape clears the filter, or cancels.
+pub async fn select_popup<T: Display>(
+    app: &mut App,
+    header: &str,
+    body: &str,
+    mut items: Vec<T>,
+) -> anyhow::Result<T> {
+    let labels = items.iter().map(ToString::to_string).collect();
+    let mut popup = SelectPopup::new(header, body, labels);
+    let Config { colors, keys, .. } = app.config().clone();
+
+    loop {
+        app.draw(|f| ui(f, &mut popup, &colors))?;
+
+        let Some(key) = app.next_key().await? else {
+            continue;
+        };
+        match key.code {
+            KeyCode::Enter => {
+                if let Some(idx) = popup.selected() {
+                    return Ok(items.swap_remove(idx));
+                }
+            }
+            KeyCode::Up => popup.move_selection(-1),
+            KeyCode::Down => popup.move_selection(1),
+            KeyCode::Esc if !popup.filter.value().is_empty() => {
+                popup.filter.reset();
+                popup.apply_filter();
+            }
+            KeyCode::Esc => return Err(anyhow::anyhow!("Exited.")),
+            // The plain keys are typed in the filter.
+            KeyCode::Char(c) if key.modifiers == KeyModifiers::CONTROL && c == keys.up => {
+                popup.move_selection(-1)
+            }
+            KeyCode::Char(c) if key.modifiers == KeyModifiers::CONTROL && c == keys.down => {
+                popup.move_selection(1)
+            }
+            KeyCode::Char(_)
+                if key
+                    .modifiers
+                    .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => {}
+            KeyCode::Char(_) | KeyCode::Backspace | KeyCode::Delete => {
+                popup.filter.handle_event(&Event::Key(key));
+                popup.apply_filter();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn ui(f: &mut Frame, popup: &mut SelectPopup, colors: &Colors) {
+    let size = f.size();
+
+    let area = centered_rect(60, 80, size);
+    f.render_widget(Clear, area);
+
+    // The body takes the lines it needs once wrapped, leaving room for a few
+    // items and the filter.
+    let body_height = wrapped_line_count(popup.body, area.width.saturating_sub(2)) as u16 + 2;
+    let chunks = Layout::default()
+        .constraints([
+            Constraint::Length(body_height.min(area.height.saturating_sub(8))),
+            Constraint::Min(5),
+            Constraint::Length(3),
+        ])
+        .split(area);
+
+    let body = Paragraph::new(popup.body).wrap(Wrap { trim: true }).block(
+        Block::default()
+            .title(popup.header)
+            .title_style(Style::default().bold())
+            .borders(Borders::ALL)
+            .fg(colors.accent),
+    );
+    f.render_widget(body, chunks[0]);
+
+    let items: Vec<ListItem> = popup
+        .visible
+        .iter()
+        .map(|&idx| ListItem::new(popup.labels[idx].as_str()))
+        .collect();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL))
+        .highlight_style(Style::default().fg(colors.accent).bold())
+        .highlight_symbol("> ");
+    f.render_stateful_widget(list, chunks[1], &mut popup.state);
+
+    let filtering = !popup.filter.value().is_empty();
+    let hint = if filtering {
+        "Enter to pick, Escape to clear the filter."
+    } else {
+        "Enter to pick, type to filter, Escape to quit."
+    };
+    let filter = Paragraph::new(popup.filter.value()).block(
+        Block::default()
+            .title(hint)
+            .borders(Borders::ALL)
+            .fg(colors.accent),
+    );
+    if filtering {
+        f.set_cursor(
+            chunks[2].x + popup.filter.visual_cursor() as u16 + 1,
+            chunks[2].y + 1,
+        );
+    }
+    f.render_widget(filter, chunks[2]);
+}
+
+/// The number of lines of `text` once wrapped at word boundaries to `width`
+/// columns, like `Wrap { trim: true }` does.
+fn wrapped_line_count(text: &str, width: u16) -> usize {
+    let width = usize::from(width.max(1));
+
+    text.lines()
+        .map(|line| {
+            let mut lines = 1;
+            let mut current = 0;
+            for word in line.split_whitespace() {
+                let len = word.chars().count();
+                if current > 0 && current + 1 + len > width {
+                    lines += 1;
+                    current = 0;
+                }
+                if current > 0 {
+                    current += 1;
+                }
+                // A word longer than the line is split over several lines.
+                current += len;
+                while current > width {
+                    lines += 1;
+                    current -= width;
+                }
+            }
+            lines
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use tui_input::Input;
+
+    use super::{wrapped_line_count, SelectPopup};
+
+    fn popup(labels: &[&str]) -> SelectPopup<'static> {
+        SelectPopup::new("", "", labels.iter().map(ToString::to_string).collect())
+    }
+
+    #[test]
+    fn filter_keeps_the_matching_items() {
+        let mut popup = popup(&["Username and password", "SSO", "Access token"]);
+        assert_eq!(popup.visible, [0, 1, 2]);
+        assert_eq!(popup.selected(), Some(0));
+
+        // The filter ignores the case, and accepts any letter.
+        popup.filter = Input::new("TOK".to_owned());
+        popup.apply_filter();
+        assert_eq!(popup.visible, [2]);
+        assert_eq!(popup.selected(), Some(2));
+
+        popup.filter = Input::new("nothing".to_owned());
+        popup.apply_filter();
+        assert!(popup.visible.is_empty());
+        assert_eq!(popup.selected(), None);
+    }
+
+    #[test]
+    fn selection_wraps_around_the_visible_items() {
+        let mut popup = popup(&["one", "two", "three"]);
+
+        popup.move_selection(-1);
+        assert_eq!(popup.selected(), Some(2));
+        popup.move_selection(1);
+        assert_eq!(popup.selected(), Some(0));
+
+        popup.filter = Input::new("t".to_owned());
+        popup.apply_filter();
+        popup.move_selection(1);
+        assert_eq!(popup.selected(), Some(2));
+        popup.move_selection(1);
+        assert_eq!(popup.selected(), Some(1));
+    }
+
+    #[test]
+    fn selection_does_nothing_without_items() {
+        let mut popup = popup(&[]);
+        popup.move_selection(1);
+        assert_eq!(popup.selected(), None);
+    }
+
+    #[test]
+    fn counts_wrapped_lines() {
+        assert_eq!(wrapped_line_count("", 10), 0);
+        assert_eq!(wrapped_line_count("short", 10), 1);
+        // Each line of the text starts a new one.
+        assert_eq!(wrapped_line_count("one\ntwo", 10), 2);
+        // "aaaa bbbb" fits, "cccc" goes on the next line.
+        assert_eq!(wrapped_line_count("aaaa bbbb cccc", 9), 2);
+        // A word longer than the width is split.
+        assert_eq!(wrapped_line_count("abcdefghij", 4), 3);
+        // A zero width doesn't loop forever.
+        assert_eq!(wrapped_line_count("a b", 0), 2);
+    }
+}
@@ -0,0 +1,36 @@
+use std::time::Instant;
+
+use ratatui::{prelude::*, widgets::*};
+
+use crate::{config::Colors, sync::Connection};
+
+/// Draw the name of the account and the state of its connection.
+///
+/// `connection` is `None` for an account that doesn't sync.
+pub fn ui(f: &mut Frame, area: Rect, name: &str, connection: Option<&Connection>, colors: &Colors) {
+    let (state, color) = match connection {
+        None => ("Not syncing".to_owned(), Color::DarkGray),
+        Some(Connection::Online) => ("Online".to_owned(), Color::Green),
+        Some(Connection::Connecting) => ("Connecting…".to_owned(), colors.accent),
+        Some(Connection::Offline {
+            error,
+            retry_at: Some(retry_at),
+        }) => {
+            let seconds = retry_at.saturating_duration_since(Instant::now()).as_secs();
+            (
+                format!("Offline, showing cached data, retrying in {seconds} s: {error}"),
+                colors.error,
+            )
+        }
+        Some(Connection::Offline {
+            error,
+            retry_at: None,
+        }) => (format!("Offline: {error}"), colors.error),
+    };
+
+    let line = Line::from(vec![
+        Span::raw(format!(" {name} ")),
+        Span::styled(format!(" {state} "), Style::default().fg(color).bold()),
+    ]);
+    f.render_widget(Paragraph::new(line), area);
+}